@@ -0,0 +1,66 @@
+extern crate shootr;
+extern crate serde_json;
+
+use shootr::replay::{Replay, Mismatch};
+use shootr::model::network::{ServerMsg, ServerMessage, WorldUpdate};
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: replay <file> [--updates]
+
+Simulates a recorded room again and checks that it ends up the same way.
+With --updates, prints the world update of every tick as JSON, one per line.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let print_updates = args.iter().any(|arg| arg == "--updates");
+    let path = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let replay = match Replay::read(Path::new(path)) {
+        Ok(replay) => replay,
+        Err(err) => {
+            eprintln!("Failed to read replay {}: {}", path, err);
+            process::exit(2);
+        }
+    };
+    let ms_per_tick = 1000 / replay.header.updates_per_sec;
+    let result = replay.run(|tick, actors| if print_updates {
+        // Nobody acknowledged anything, so every update contains the full state
+        let update = WorldUpdate::new(u64::from(tick) * ms_per_tick, 0, actors, None);
+        let msg = ServerMsg::new(tick, ServerMessage::WorldUpdate(update));
+        println!(
+            "{}",
+            serde_json::to_string(&msg).expect("Failed to serialize world update")
+        );
+    });
+    match result {
+        Ok(()) if replay.end.is_some() => {
+            eprintln!("Replayed {} ticks, final state matches", replay.ticks.len())
+        }
+        Ok(()) => eprintln!("Replayed {} ticks, no final state recorded", replay.ticks.len()),
+        Err(Mismatch::Checksum {
+                tick,
+                expected,
+                actual,
+            }) => {
+            eprintln!(
+                "Diverged in tick {}: Expected checksum {:08x}, got {:08x}",
+                tick,
+                expected,
+                actual
+            );
+            process::exit(1);
+        }
+        Err(Mismatch::FinalState { tick }) => {
+            eprintln!("Diverged: Final state after tick {} doesn't match", tick);
+            process::exit(1);
+        }
+    }
+}
@@ -2,15 +2,19 @@ extern crate specs;
 
 use self::specs::World;
 use model::comp::*;
-use model::game::Vector;
+use model::game::{Vector, Score, Goal, Slots};
+use model::network::SnapshotHistory;
+use config::GameConfig;
 use util::SeqIdGen;
 
 use std::sync::RwLock;
 
-pub fn prepare_world(world: &mut World) {
+pub fn prepare_world(world: &mut World, config: &GameConfig) {
     register_components(world);
-    add_constraints(world);
+    add_constraints(world, config);
     add_utils(world);
+    add_game_state(world);
+    add_network_state(world);
 }
 
 
@@ -33,24 +37,42 @@ fn register_components(world: &mut World) {
         ToSpawn,
         ToDespawn,
         Actor,
+        Collision,
+        Paddle,
+        Spectator,
+        Promoted,
+        Suspended,
+        Resumed,
+        Bot,
     );
 }
 
-fn add_constraints(world: &mut World) {
+fn add_constraints(world: &mut World, config: &GameConfig) {
     world.add_resource(Bounds {
-        min: Acc::from(Vector { x: -5, y: -5 }),
-        max: Acc::from(Vector { x: 5, y: 5 }),
+        min: Acc::from(Vector::new(-4500, -4500)),
+        max: Acc::from(Vector::new(4500, 4500)),
     });
     world.add_resource(Bounds {
-        min: Vel::from(Vector { x: -50, y: -50 }),
-        max: Vel::from(Vector { x: 50, y: 50 }),
+        min: Vel::from(Vector::new(-1500, -1500)),
+        max: Vel::from(Vector::new(1500, 1500)),
     });
     world.add_resource(Bounds {
-        min: Pos::from(Vector { x: 0, y: 0 }),
-        max: Pos::from(Vector { x: 1000, y: 1000 }),
+        min: Pos::from(Vector::new(0, 0)),
+        max: Pos::from(Vector::new(config.arena.width, config.arena.height)),
     });
+    world.add_resource(config.clone());
 }
 
 fn add_utils(world: &mut World) {
     world.add_resource(RwLock::new(SeqIdGen::default()));
 }
+
+fn add_game_state(world: &mut World) {
+    world.add_resource(RwLock::new(Slots::default()));
+    world.add_resource(RwLock::new(Score::default()));
+    world.add_resource(RwLock::new(Vec::<Goal>::new()));
+}
+
+fn add_network_state(world: &mut World) {
+    world.add_resource(RwLock::new(SnapshotHistory::default()));
+}
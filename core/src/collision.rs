@@ -1,7 +1,9 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 use model::game::Vector;
+use model::fixed::Fixed;
 use model::comp::Pos;
+use error::Error;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Bounds {
@@ -21,21 +23,385 @@ impl Bounds {
             other.x + other.width / 2 <= self.x + self.width / 2 &&
             other.y + other.height / 2 <= self.y + self.height / 2
     }
+    pub fn sweep(&self, vel: &Vector, other: &Self) -> Option<(Fixed, Vector)> {
+        let (x_entry, x_exit) = sweep_axis(self.x, self.width, other.x, other.width, vel.x)?;
+        let (y_entry, y_exit) = sweep_axis(self.y, self.height, other.y, other.height, vel.y)?;
+        let entry = x_entry.max(y_entry);
+        let exit = x_exit.min(y_exit);
+        if entry >= exit || entry < Fixed::zero() || entry > Fixed::from(1) {
+            return None;
+        }
+        let normal = if x_entry >= y_entry {
+            Vector::new(-vel.x.signum(), 0)
+        } else {
+            Vector::new(0, -vel.y.signum())
+        };
+        Some((entry, normal))
+    }
+
+    /// Whether the shapes filling both bounds overlap. The normal of the contact points
+    /// away from the other shape.
+    pub fn contact(&self, shape: Shape, other: &Self, other_shape: Shape) -> Option<Contact> {
+        match (shape, other_shape) {
+            (Shape::Aabb, Shape::Aabb) => aabb_contact(self, other),
+            (Shape::Circle, Shape::Circle) => circle_contact(self, other),
+            (Shape::Circle, Shape::Aabb) => circle_aabb_contact(self, other),
+            (Shape::Aabb, Shape::Circle) => {
+                circle_aabb_contact(other, self).map(|contact| contact.flipped())
+            }
+        }
+    }
+
+    pub fn contains_shape(&self, shape: Shape, other: &Self, other_shape: Shape) -> bool {
+        let (x, y, _, _) = doubled(self);
+        let (other_x, other_y, other_width, other_height) = doubled(other);
+        let (dx, dy) = ((x - other_x).abs(), (y - other_y).abs());
+        match (shape, other_shape) {
+            (Shape::Aabb, Shape::Aabb) => self.contains(other),
+            (Shape::Aabb, Shape::Circle) => self.contains(&circle_box(other)),
+            (Shape::Circle, Shape::Circle) => {
+                let reach = diameter(self) - diameter(other);
+                reach >= 0 && dx * dx + dy * dy <= reach * reach
+            }
+            (Shape::Circle, Shape::Aabb) => {
+                // The farthest corner decides
+                let (corner_x, corner_y) = (dx + other_width, dy + other_height);
+                corner_x * corner_x + corner_y * corner_y <= diameter(self) * diameter(self)
+            }
+        }
+    }
+
+    /// Like `sweep`, but with the shapes filling both bounds
+    pub fn sweep_shape(
+        &self,
+        shape: Shape,
+        vel: &Vector,
+        other: &Self,
+        other_shape: Shape,
+    ) -> Option<(Fixed, Vector)> {
+        match (shape, other_shape) {
+            (Shape::Aabb, Shape::Aabb) => self.sweep(vel, other),
+            (Shape::Circle, Shape::Circle) => {
+                let center = Vector::new(other.x - self.x, other.y - self.y);
+                let reach = half(diameter(self) + diameter(other));
+                sweep_circle(vel, &center, reach)
+            }
+            (Shape::Circle, Shape::Aabb) => sweep_circle_aabb(self, vel, other),
+            (Shape::Aabb, Shape::Circle) => {
+                // Moving the box is the same as moving the circle the other way
+                let reversed = Vector {
+                    x: -vel.x,
+                    y: -vel.y,
+                };
+                let (time, normal) = sweep_circle_aabb(other, &reversed, self)?;
+                Some((time, Vector { x: -normal.x, y: -normal.y }))
+            }
+        }
+    }
+}
+
+/// Exact outline of an object, always centered in and filling out its bounds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shape {
+    Aabb,
+    /// As wide as the narrower side of its bounds
+    Circle,
+}
+impl Default for Shape {
+    fn default() -> Self {
+        Shape::Aabb
+    }
+}
+
+/// How two overlapping shapes touch
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contact {
+    /// Unit vector pointing away from the other shape
+    pub normal: Vector,
+    /// How far to move along the normal until they no longer overlap
+    pub penetration: Fixed,
+}
+impl Contact {
+    fn flipped(self) -> Self {
+        Contact {
+            normal: Vector {
+                x: -self.normal.x,
+                y: -self.normal.y,
+            },
+            penetration: self.penetration,
+        }
+    }
+}
+
+/// Center and size in half units, so that halving odd sizes stays exact
+fn doubled(bounds: &Bounds) -> (i64, i64, i64, i64) {
+    (
+        i64::from(bounds.x) * 2,
+        i64::from(bounds.y) * 2,
+        i64::from(bounds.width),
+        i64::from(bounds.height),
+    )
+}
+
+/// Also the radius of the circle in half units
+fn diameter(bounds: &Bounds) -> i64 {
+    i64::from(bounds.width.min(bounds.height))
+}
+
+fn circle_box(bounds: &Bounds) -> Bounds {
+    let diameter = bounds.width.min(bounds.height);
+    Bounds {
+        width: diameter,
+        height: diameter,
+        ..bounds.clone()
+    }
+}
+
+fn half(doubled: i64) -> Fixed {
+    Fixed::from(doubled as i32) / 2
+}
+
+/// Direction of a distance in half units, along with its length in whole units
+fn normalized(dx: i64, dy: i64) -> (Vector, Fixed) {
+    let (x, y) = (half(dx), half(dy));
+    let distance = x.hypot(y);
+    let normal = Vector {
+        x: x / distance,
+        y: y / distance,
+    };
+    (normal, distance)
+}
+
+/// Ties push us to the right or down
+fn side(delta: i64) -> i32 {
+    if delta < 0 { -1 } else { 1 }
+}
+
+fn aabb_contact(a: &Bounds, b: &Bounds) -> Option<Contact> {
+    let (a_x, a_y, a_width, a_height) = doubled(a);
+    let (b_x, b_y, b_width, b_height) = doubled(b);
+    let (dx, dy) = (a_x - b_x, a_y - b_y);
+    let overlap_x = a_width + b_width - dx.abs();
+    let overlap_y = a_height + b_height - dy.abs();
+    if overlap_x <= 0 || overlap_y <= 0 {
+        return None;
+    }
+    // The shallower axis is the quickest way out
+    let contact = if overlap_x <= overlap_y {
+        Contact {
+            normal: Vector::new(side(dx), 0),
+            penetration: half(overlap_x),
+        }
+    } else {
+        Contact {
+            normal: Vector::new(0, side(dy)),
+            penetration: half(overlap_y),
+        }
+    };
+    Some(contact)
+}
+
+fn circle_contact(a: &Bounds, b: &Bounds) -> Option<Contact> {
+    let (a_x, a_y, _, _) = doubled(a);
+    let (b_x, b_y, _, _) = doubled(b);
+    let (dx, dy) = (a_x - b_x, a_y - b_y);
+    let reach = diameter(a) + diameter(b);
+    if dx * dx + dy * dy >= reach * reach {
+        return None;
+    }
+    if dx == 0 && dy == 0 {
+        return Some(Contact {
+            normal: Vector::new(1, 0),
+            penetration: half(reach),
+        });
+    }
+    let (normal, distance) = normalized(dx, dy);
+    Some(Contact {
+        normal,
+        penetration: half(reach) - distance,
+    })
+}
+
+fn circle_aabb_contact(circle: &Bounds, aabb: &Bounds) -> Option<Contact> {
+    let (x, y, _, _) = doubled(circle);
+    let radius = diameter(circle);
+    let (box_x, box_y, box_width, box_height) = doubled(aabb);
+    let (left, right) = (box_x - box_width, box_x + box_width);
+    let (top, bottom) = (box_y - box_height, box_y + box_height);
+    let (dx, dy) = (x - x.max(left).min(right), y - y.max(top).min(bottom));
+    if dx == 0 && dy == 0 {
+        // The center is inside the box, leave through the closest edge
+        let edges = [
+            (x - left, Vector::new(-1, 0)),
+            (right - x, Vector::new(1, 0)),
+            (y - top, Vector::new(0, -1)),
+            (bottom - y, Vector::new(0, 1)),
+        ];
+        let &(distance, ref normal) = edges
+            .iter()
+            .min_by_key(|&&(distance, _)| distance)
+            .expect("Failed to find closest edge: No edges");
+        return Some(Contact {
+            normal: normal.clone(),
+            penetration: half(radius + distance),
+        });
+    }
+    if dx * dx + dy * dy >= radius * radius {
+        return None;
+    }
+    let (normal, distance) = normalized(dx, dy);
+    Some(Contact {
+        normal,
+        penetration: half(radius) - distance,
+    })
+}
+
+/// When a point starting at the origin gets within `radius` of `center`, as a fraction of `vel`,
+/// and the normal of the circle where it does
+fn sweep_circle(vel: &Vector, center: &Vector, radius: Fixed) -> Option<(Fixed, Vector)> {
+    let speed = vel.x.hypot(vel.y);
+    if speed == Fixed::zero() || radius <= Fixed::zero() {
+        return None;
+    }
+    // Projected onto the path in raw bits, where the products have room to spare
+    let bits = |val: Fixed| i64::from(val.to_bits());
+    let speed_bits = bits(speed);
+    let dot = bits(vel.x) * bits(center.x) + bits(vel.y) * bits(center.y);
+    let cross = bits(vel.x) * bits(center.y) - bits(vel.y) * bits(center.x);
+    let along = Fixed::from_bits((dot / speed_bits) as i32);
+    let aside = Fixed::from_bits((cross / speed_bits) as i32).abs();
+    if aside > radius {
+        return None;
+    }
+    // Half the chord the path cuts through the circle
+    let distance = along - radius.leg(aside);
+    if distance < Fixed::zero() || distance > speed {
+        return None;
+    }
+    // Straight from the distance, going through the time would lose precision
+    let travelled = |vel: Fixed| Fixed::from_bits((bits(vel) * bits(distance) / speed_bits) as i32);
+    let normal = Vector {
+        x: (travelled(vel.x) - center.x) / radius,
+        y: (travelled(vel.y) - center.y) / radius,
+    };
+    Some((distance / speed, normal))
+}
+
+fn sweep_circle_aabb(circle: &Bounds, vel: &Vector, aabb: &Bounds) -> Option<(Fixed, Vector)> {
+    let start = Vector::new(circle.x - aabb.x, circle.y - aabb.y);
+    let (half_width, half_height) = (half(i64::from(aabb.width)), half(i64::from(aabb.height)));
+    // Reaching the box grown by the radius is necessary, but not enough at its corners
+    let grown = circle_box(circle);
+    let (x, y) = match grown.sweep(vel, aabb) {
+        Some((time, normal)) => {
+            let (x, y) = (start.x + vel.x * time, start.y + vel.y * time);
+            if x.abs() <= half_width || y.abs() <= half_height {
+                return Some((time, normal));
+            }
+            (x, y)
+        }
+        // Already within the grown box, but still clear of its rounded corner
+        None if grown.intersects(aabb) && circle_aabb_contact(circle, aabb).is_none() => {
+            (start.x, start.y)
+        }
+        None => return None,
+    };
+    // Coming in diagonally, only the rounded corner can be hit
+    let corner = Vector {
+        x: half_width * x.signum(),
+        y: half_height * y.signum(),
+    };
+    let radius = half(diameter(circle));
+    let center = Vector {
+        x: corner.x - start.x,
+        y: corner.y - start.y,
+    };
+    sweep_circle(vel, &center, radius)
+}
+
+/// Which layers an object is on and which ones it collides with, both as bitmasks.
+/// Two objects only collide if each of them collides with a layer the other one is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layers {
+    pub member: u32,
+    pub collides_with: u32,
+}
+impl Layers {
+    pub fn new(member: u32, collides_with: u32) -> Self {
+        Layers {
+            member,
+            collides_with,
+        }
+    }
+    pub fn collides(&self, other: &Self) -> bool {
+        self.collides_with & other.member != 0 && other.collides_with & self.member != 0
+    }
+}
+/// On every layer and colliding with everything
+impl Default for Layers {
+    fn default() -> Self {
+        Layers::new(!0, !0)
+    }
+}
+
+fn sweep_axis(
+    pos: i32,
+    size: i32,
+    other_pos: i32,
+    other_size: i32,
+    vel: Fixed,
+) -> Option<(Fixed, Fixed)> {
+    // In half units, so that halving odd sizes stays exact
+    let distance = (i64::from(other_pos) - i64::from(pos)) * 2;
+    let reach = i64::from(size) + i64::from(other_size);
+    if vel == Fixed::zero() {
+        // Not moving on this axis, so we either always or never overlap on it
+        return if distance.abs() < reach {
+            Some((Fixed::from_bits(i32::min_value()), Fixed::from_bits(i32::max_value())))
+        } else {
+            None
+        };
+    }
+    let vel = i64::from(vel.to_bits()) * 2;
+    let scale = i64::from(Fixed::from(1).to_bits());
+    let a = Fixed::ratio((distance - reach) * scale, vel);
+    let b = Fixed::ratio((distance + reach) * scale, vel);
+    Some((a.min(b), a.max(b)))
 }
 
 #[derive(Clone, Debug)]
 pub struct CollisionObject<'a, Id: 'a> {
     pub id: &'a Id,
     pub bounds: &'a Bounds,
+    pub shape: Shape,
+    /// Set by queries for intersections, pointing away from this object
+    pub contact: Option<Contact>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sweep<'a, Id: 'a> {
+    pub id: &'a Id,
+    pub bounds: &'a Bounds,
+    /// Fraction of the velocity that can be travelled before touching
+    pub time: Fixed,
+    pub normal: Vector,
 }
 
-type SpatialHash = Vector;
+/// Coordinates of a cell in the grid
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct SpatialHash {
+    x: i32,
+    y: i32,
+}
 type Bucket<Id> = Vec<Id>;
+#[derive(Clone)]
 pub struct World<Id> {
     width: i32,
     height: i32,
     cell_size: i32,
     entities: HashMap<Id, Bounds>,
+    shapes: HashMap<Id, Shape>,
+    layers: HashMap<Id, Layers>,
     grid: HashMap<SpatialHash, Bucket<Id>>,
 }
 
@@ -44,11 +410,13 @@ where
     Id: Hash + PartialEq + Eq + Clone,
 {
     pub fn new(width: i32, height: i32) -> Self {
+        World::with_cell_size(width, height, 100)
+    }
+    pub fn with_cell_size(width: i32, height: i32, cell_size: i32) -> Self {
         let mut grid = HashMap::new();
-        let cell_size: i32 = 100;
         for i in 0..width / cell_size {
             for j in 0..height / cell_size {
-                grid.insert(Vector { x: i, y: j }, Bucket::new());
+                grid.insert(SpatialHash { x: i, y: j }, Bucket::new());
             }
         }
 
@@ -57,42 +425,55 @@ where
             height,
             cell_size,
             entities: HashMap::new(),
+            shapes: HashMap::new(),
+            layers: HashMap::new(),
             grid,
         }
     }
-    pub fn add(&mut self, id: Id, bounds: Bounds) {
-        assert!(
-            bounds.x + bounds.width / 2 > 0 && bounds.y + bounds.height / 2 > 0 &&
-                bounds.x - bounds.width / 2 < self.width &&
-                bounds.y - bounds.height / 2 < self.height
-        );
+    /// Leaves the world untouched on error
+    pub fn try_add(&mut self, id: Id, bounds: Bounds) -> Result<(), Error> {
+        self.try_add_with(id, bounds, Shape::default(), Layers::default())
+    }
+
+    /// Leaves the world untouched on error
+    pub fn try_add_with(
+        &mut self,
+        id: Id,
+        bounds: Bounds,
+        shape: Shape,
+        layers: Layers,
+    ) -> Result<(), Error> {
+        if !self.overlaps(&bounds) {
+            return Err(Error::OutOfBounds);
+        }
+        if self.entities.contains_key(&id) {
+            return Err(Error::DuplicateId);
+        }
         let spatial_hash = self.hash_bounds(&bounds);
-        let old = self.entities.insert(id.clone(), bounds);
-        assert!(
-            old.is_none(),
-            "Failed to add new entity: Id already registered"
-        );
+        self.entities.insert(id.clone(), bounds);
+        self.shapes.insert(id.clone(), shape);
+        self.layers.insert(id.clone(), layers);
         self.grid
             .entry(spatial_hash)
             .or_insert_with(Bucket::new)
             .push(id);
+        Ok(())
     }
 
-    pub fn place(&mut self, id: &Id, pos: &Pos) {
-        let mut bounds = self.entities
-            .get(id)
-            .expect("Failed to place entity: Id doesn't exist")
-            .clone();
+    /// Leaves the world untouched on error
+    pub fn try_place(&mut self, id: &Id, pos: &Pos) -> Result<(), Error> {
+        let mut bounds = match self.entities.get(id) {
+            Some(bounds) => bounds.clone(),
+            None => return Err(Error::UnknownId),
+        };
         let old_spatial_hash = self.hash_bounds(&bounds);
 
-        // Create new bounds
-        bounds.x = pos.x;
-        bounds.y = pos.y;
-        assert!(
-            bounds.x + bounds.width / 2 > 0 && bounds.y + bounds.height / 2 > 0 &&
-                bounds.x - bounds.width / 2 < self.width &&
-                bounds.y - bounds.height / 2 < self.height
-        );
+        // Bounds stick to whole units, positions in between get rounded
+        bounds.x = pos.x.round();
+        bounds.y = pos.y.round();
+        if !self.overlaps(&bounds) {
+            return Err(Error::OutOfBounds);
+        }
 
         let new_spatial_hash = self.hash_bounds(&bounds);
         self.entities.insert(id.clone(), bounds);
@@ -112,11 +493,26 @@ where
                 .or_insert_with(Bucket::new)
                 .push(id);
         }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &Id) -> Option<&Bounds> {
+        self.entities.get(id)
+    }
+
+    pub fn get_shape(&self, id: &Id) -> Option<Shape> {
+        self.shapes.get(id).cloned()
+    }
+
+    pub fn get_layers(&self, id: &Id) -> Option<&Layers> {
+        self.layers.get(id)
     }
 
     pub fn remove(&mut self, id: &Id) -> Option<Bounds> {
         match self.entities.remove(id) {
             Some(bounds) => {
+                self.shapes.remove(id);
+                self.layers.remove(id);
                 let spatial_hash = self.hash_bounds(&bounds);
                 let bucket = self.grid.get_mut(&spatial_hash).expect(
                     "Removed id from entity list but didn't find its spatial hash in grid",
@@ -135,45 +531,32 @@ where
     where
         T: FnMut(CollisionObject<Id>, CollisionObject<Id>),
     {
-        for (spatial_hash, bucket) in &self.grid {
+        // Visit cells in a fixed order so callbacks fire the same way on every run
+        let mut spatial_hashes: Vec<&SpatialHash> = self.grid.keys().collect();
+        spatial_hashes.sort();
+        for spatial_hash in spatial_hashes {
             let neighbors = self.get_half_neighbors(spatial_hash);
             let own_bucket = &self.grid[spatial_hash];
 
             // Collisions in own bucket
             let mut already_handled = HashSet::new();
             for id in own_bucket {
-                let bounds = &self.entities[id];
                 for other_id in own_bucket {
                     if *id == *other_id || already_handled.contains(&(id, other_id)) {
                         continue;
                     }
-                    let other_bounds = &self.entities[other_id];
-                    if bounds.intersects(other_bounds) {
-                        cb(
-                            CollisionObject { id, bounds },
-                            CollisionObject {
-                                id: other_id,
-                                bounds: other_bounds,
-                            },
-                        );
+                    if let Some((object, other)) = self.intersection(id, other_id) {
+                        cb(object, other);
                     }
                     already_handled.insert((other_id, id));
                 }
             }
             // Collisions in neighbors
             for neighbor_bucket in neighbors {
-                for id in bucket {
-                    let bounds = &self.entities[id];
+                for id in own_bucket {
                     for neighbor_id in neighbor_bucket {
-                        let neighbor_bounds = &self.entities[neighbor_id];
-                        if neighbor_bounds.intersects(bounds) {
-                            cb(
-                                CollisionObject { id, bounds },
-                                CollisionObject {
-                                    id: neighbor_id,
-                                    bounds: neighbor_bounds,
-                                },
-                            );
+                        if let Some((object, neighbor)) = self.intersection(id, neighbor_id) {
+                            cb(object, neighbor);
                         }
                     }
                 }
@@ -181,54 +564,165 @@ where
         }
     }
 
-    pub fn query_intersects_other<T>(&self, bounds: &Bounds, mut cb: T)
-    where
+    pub fn query_intersects_other<T>(
+        &self,
+        bounds: &Bounds,
+        shape: Shape,
+        layers: &Layers,
+        mut cb: T,
+    ) where
         T: FnMut(CollisionObject<Id>),
     {
-        self.query_other(bounds, |other| if bounds.intersects(other.bounds) {
-            cb(other);
+        self.query_other(bounds, layers, |other| {
+            let contact = bounds.contact(shape, other.bounds, other.shape);
+            if contact.is_some() {
+                cb(CollisionObject { contact, ..other });
+            }
         });
     }
 
-    pub fn query_intersects_id<T>(&self, id: &Id, mut cb: T)
+    pub fn query_intersects_id<T>(&self, id: &Id, mut cb: T) -> Result<(), Error>
     where
         T: FnMut(CollisionObject<Id>),
     {
-        let bounds = self.entities.get(id).expect(
-            "Failed to query for id: Id not registered",
-        );
-        self.query_other(bounds, |other| if *id != *other.id &&
-            bounds.intersects(other.bounds)
-        {
-            cb(other);
+        let bounds = self.entities.get(id).ok_or(Error::UnknownId)?;
+        let shape = self.shapes[id];
+        self.query_other(bounds, &self.layers[id], |other| {
+            if *id == *other.id {
+                return;
+            }
+            let contact = bounds.contact(shape, other.bounds, other.shape);
+            if contact.is_some() {
+                cb(CollisionObject { contact, ..other });
+            }
         });
+        Ok(())
     }
 
-    pub fn query_contains_other<T>(&self, bounds: &Bounds, mut cb: T)
-    where
+    pub fn query_contains_other<T>(
+        &self,
+        bounds: &Bounds,
+        shape: Shape,
+        layers: &Layers,
+        mut cb: T,
+    ) where
         T: FnMut(CollisionObject<Id>),
     {
-        self.query_other(bounds, |other| if other.bounds.contains(bounds) {
-            cb(other);
+        self.query_other(bounds, layers, |other| {
+            if other.bounds.contains_shape(other.shape, bounds, shape) {
+                cb(other);
+            }
         });
     }
 
-    fn query_other<T>(&self, bounds: &Bounds, mut cb: T)
+    pub fn query_sweep_other(
+        &self,
+        bounds: &Bounds,
+        shape: Shape,
+        layers: &Layers,
+        vel: &Vector,
+    ) -> Option<Sweep<Id>> {
+        self.query_sweep(bounds, shape, layers, vel, None)
+    }
+
+    pub fn query_sweep_id(&self, id: &Id, vel: &Vector) -> Result<Option<Sweep<Id>>, Error> {
+        let bounds = self.entities.get(id).ok_or(Error::UnknownId)?;
+        Ok(self.query_sweep(bounds, self.shapes[id], &self.layers[id], vel, Some(id)))
+    }
+
+    fn query_sweep(
+        &self,
+        bounds: &Bounds,
+        shape: Shape,
+        layers: &Layers,
+        vel: &Vector,
+        ignore: Option<&Id>,
+    ) -> Option<Sweep<Id>> {
+        let mut earliest: Option<Sweep<Id>> = None;
+        for bucket in self.get_swept_buckets(bounds, vel) {
+            for id in bucket {
+                if Some(id) == ignore || !layers.collides(&self.layers[id]) {
+                    continue;
+                }
+                let other_bounds = &self.entities[id];
+                let sweep = bounds.sweep_shape(shape, vel, other_bounds, self.shapes[id]);
+                if let Some((time, normal)) = sweep {
+                    let is_earlier = match earliest {
+                        Some(ref sweep) => time < sweep.time,
+                        None => true,
+                    };
+                    if is_earlier {
+                        earliest = Some(Sweep {
+                            id,
+                            bounds: other_bounds,
+                            time,
+                            normal,
+                        });
+                    }
+                }
+            }
+        }
+        earliest
+    }
+
+    fn query_other<T>(&self, bounds: &Bounds, layers: &Layers, mut cb: T)
     where
         T: FnMut(CollisionObject<Id>),
     {
         let spatial_hash = self.hash_bounds(bounds);
         let mut neighbors = self.get_all_neighbors(&spatial_hash);
-        let own_bucket = &self.grid[&spatial_hash];
-        neighbors.push(own_bucket);
+        if let Some(own_bucket) = self.grid.get(&spatial_hash) {
+            neighbors.push(own_bucket);
+        }
         for bucket in neighbors {
             for id in bucket {
-                let bounds = &self.entities[id];
-                cb(CollisionObject { id, bounds })
+                if !layers.collides(&self.layers[id]) {
+                    continue;
+                }
+                cb(CollisionObject {
+                    id,
+                    bounds: &self.entities[id],
+                    shape: self.shapes[id],
+                    contact: None,
+                })
             }
         }
     }
 
+    /// Both objects if they collide, each with its contact to the other one
+    #[allow(type_complexity)]
+    fn intersection<'a>(
+        &'a self,
+        id: &'a Id,
+        other_id: &'a Id,
+    ) -> Option<(CollisionObject<'a, Id>, CollisionObject<'a, Id>)> {
+        if !self.layers[id].collides(&self.layers[other_id]) {
+            return None;
+        }
+        let (bounds, shape) = (&self.entities[id], self.shapes[id]);
+        let (other_bounds, other_shape) = (&self.entities[other_id], self.shapes[other_id]);
+        let contact = other_bounds.contact(other_shape, bounds, shape)?;
+        let object = CollisionObject {
+            id,
+            bounds,
+            shape,
+            contact: Some(contact.clone()),
+        };
+        let other = CollisionObject {
+            id: other_id,
+            bounds: other_bounds,
+            shape: other_shape,
+            contact: Some(contact.flipped()),
+        };
+        Some((object, other))
+    }
+
+    fn overlaps(&self, bounds: &Bounds) -> bool {
+        bounds.x + bounds.width / 2 > 0 && bounds.y + bounds.height / 2 > 0 &&
+            bounds.x - bounds.width / 2 < self.width &&
+            bounds.y - bounds.height / 2 < self.height
+    }
+
     fn hash_bounds(&self, bounds: &Bounds) -> SpatialHash {
         SpatialHash {
             x: bounds.x / self.cell_size,
@@ -257,6 +751,30 @@ where
         neighbors
     }
 
+    fn get_swept_buckets(&self, bounds: &Bounds, vel: &Vector) -> Vec<&Bucket<Id>> {
+        let start = self.hash_bounds(&Bounds {
+            x: bounds.x + vel.x.floor().min(0) - bounds.width / 2,
+            y: bounds.y + vel.y.floor().min(0) - bounds.height / 2,
+            ..bounds.clone()
+        });
+        let end = self.hash_bounds(&Bounds {
+            x: bounds.x + vel.x.ceil().max(0) + bounds.width / 2,
+            y: bounds.y + vel.y.ceil().max(0) + bounds.height / 2,
+            ..bounds.clone()
+        });
+
+        let mut buckets = Vec::new();
+        // Objects are hashed by their center, so they can reach one cell further
+        for x in start.x - 1..end.x + 2 {
+            for y in start.y - 1..end.y + 2 {
+                if let Some(bucket) = self.grid.get(&SpatialHash { x, y }) {
+                    buckets.push(bucket);
+                }
+            }
+        }
+        buckets
+    }
+
     fn get_all_neighbors(&self, spatial_hash: &SpatialHash) -> Vec<&Bucket<Id>> {
         let mut neighbors = self.get_half_neighbors(spatial_hash);
         let x = spatial_hash.x;
@@ -278,6 +796,77 @@ where
     }
 }
 
+/// An entity as it was in an earlier tick, everything needed to sweep against it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PastEntity {
+    pub bounds: Bounds,
+    pub shape: Shape,
+    pub layers: Layers,
+}
+
+/// Where some entities of a world were over the last few ticks
+pub struct History<Id> {
+    ticks: VecDeque<HashMap<Id, PastEntity>>,
+    capacity: usize,
+}
+
+impl<Id> History<Id>
+where
+    Id: Hash + PartialEq + Eq + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        History {
+            ticks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Remembers where the given entities are right now.
+    /// Forgets the oldest tick once we are at capacity.
+    pub fn push<'a, I>(&mut self, world: &World<Id>, ids: I)
+    where
+        I: IntoIterator<Item = &'a Id>,
+        Id: 'a,
+    {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ticks.len() == self.capacity {
+            self.ticks.pop_front();
+        }
+        let entities = ids.into_iter()
+            .filter_map(|id| match (world.get(id), world.get_shape(id), world.get_layers(id)) {
+                (Some(bounds), Some(shape), Some(layers)) => {
+                    let past = PastEntity {
+                        bounds: bounds.clone(),
+                        shape,
+                        layers: *layers,
+                    };
+                    Some((id.clone(), past))
+                }
+                _ => None,
+            })
+            .collect();
+        self.ticks.push_back(entities);
+    }
+
+    /// The entity as it was the given number of pushes ago, 0 being the latest one
+    pub fn rewind(&self, ticks: usize, id: &Id) -> Option<&PastEntity> {
+        if ticks >= self.ticks.len() {
+            return None;
+        }
+        self.ticks[self.ticks.len() - 1 - ticks].get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -445,11 +1034,10 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(1, bounds);
+        world.try_add(1, bounds).unwrap();
     }
 
     #[test]
-    #[should_panic]
     fn add_too_low() {
         let mut world = World::new(1000, 1000);
         let bounds = Bounds {
@@ -458,11 +1046,10 @@ mod test {
             width: 1,
             height: 1,
         };
-        world.add(1, bounds);
+        assert_eq!(Err(Error::OutOfBounds), world.try_add(1, bounds));
     }
 
     #[test]
-    #[should_panic]
     fn add_too_low_edge() {
         let mut world = World::new(1000, 1000);
         let bounds = Bounds {
@@ -471,12 +1058,11 @@ mod test {
             width: 5,
             height: 5,
         };
-        world.add(1, bounds);
+        assert_eq!(Err(Error::OutOfBounds), world.try_add(1, bounds));
     }
 
 
     #[test]
-    #[should_panic]
     fn add_too_high() {
         let mut world = World::new(1000, 1000);
         let bounds = Bounds {
@@ -485,12 +1071,11 @@ mod test {
             width: 1,
             height: 1,
         };
-        world.add(1, bounds);
+        assert_eq!(Err(Error::OutOfBounds), world.try_add(1, bounds));
     }
 
 
     #[test]
-    #[should_panic]
     fn add_too_high_edge() {
         let mut world = World::new(1000, 1000);
         let bounds = Bounds {
@@ -499,33 +1084,26 @@ mod test {
             width: 1,
             height: 5,
         };
-        world.add(1, bounds);
+        assert_eq!(Err(Error::OutOfBounds), world.try_add(1, bounds));
     }
 
     #[test]
-    #[should_panic]
-    fn add_existing() {
+    fn place() {
         let mut world = World::new(1000, 1000);
         let id = 1;
-        let bounds_a = Bounds {
+        let bounds = Bounds {
             x: 0,
             y: 0,
             width: 10,
             height: 10,
         };
-        world.add(id, bounds_a.clone());
-        let bounds_b = Bounds {
-            x: 1,
-            y: 1,
-            width: 10,
-            height: 10,
-        };
-        world.add(id, bounds_b);
+        world.try_add(id, bounds.clone()).unwrap();
+        world.try_place(&id, &Vector::new(30, 50).into()).unwrap();
     }
 
 
     #[test]
-    fn place() {
+    fn place_rounds_to_whole_units() {
         let mut world = World::new(1000, 1000);
         let id = 1;
         let bounds = Bounds {
@@ -534,13 +1112,19 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds.clone());
-        world.place(&id, &Vector { x: 30, y: 50 }.into());
+        world.try_add(id, bounds.clone()).unwrap();
+        let pos = Vector {
+            x: Fixed::from_f64(30.5),
+            y: Fixed::from_f64(49.25),
+        };
+        world.try_place(&id, &pos.into()).unwrap();
+        let placed = world.get(&id).unwrap();
+        assert_eq!(31, placed.x);
+        assert_eq!(49, placed.y);
     }
 
 
     #[test]
-    #[should_panic]
     fn place_too_low() {
         let mut world = World::new(1000, 1000);
         let id = 1;
@@ -550,13 +1134,12 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds.clone());
-        world.place(&id, &Vector { x: -999, y: 50 }.into());
+        world.try_add(id, bounds.clone()).unwrap();
+        assert_eq!(Err(Error::OutOfBounds), world.try_place(&id, &Vector::new(-999, 50).into()));
     }
 
 
     #[test]
-    #[should_panic]
     fn place_deleted() {
         let mut world = World::new(1000, 1000);
         let id = 1;
@@ -566,14 +1149,13 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds.clone());
+        world.try_add(id, bounds.clone()).unwrap();
         world.remove(&id);
-        world.place(&id, &Vector { x: 30, y: 50 }.into());
+        assert_eq!(Err(Error::UnknownId), world.try_place(&id, &Vector::new(30, 50).into()));
     }
 
 
     #[test]
-    #[should_panic]
     fn place_nonexistant() {
         let mut world = World::new(1000, 1000);
         let id = 1;
@@ -583,8 +1165,53 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds.clone());
-        world.place(&(id + 1), &Vector { x: 30, y: 50 }.into());
+        world.try_add(id, bounds.clone()).unwrap();
+        assert_eq!(Err(Error::UnknownId), world.try_place(&(id + 1), &Vector::new(30, 50).into()));
+    }
+
+
+    #[test]
+    fn try_add_existing() {
+        let mut world = World::new(1000, 1000);
+        let id = 1;
+        let bounds_a = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        world.try_add(id, bounds_a.clone()).unwrap();
+        let bounds_b = Bounds {
+            x: 500,
+            y: 500,
+            width: 10,
+            height: 10,
+        };
+        assert_eq!(Err(Error::DuplicateId), world.try_add(id, bounds_b));
+        assert_eq!(Some(&bounds_a), world.get(&id));
+    }
+
+
+    #[test]
+    fn try_place_errors() {
+        let mut world = World::new(1000, 1000);
+        let id = 1;
+        let bounds = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        world.try_add(id, bounds.clone()).unwrap();
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            world.try_place(&id, &Vector::new(-999, 50).into())
+        );
+        assert_eq!(Some(&bounds), world.get(&id));
+        assert_eq!(
+            Err(Error::UnknownId),
+            world.try_place(&(id + 1), &Vector::new(30, 50).into())
+        );
     }
 
 
@@ -598,9 +1225,9 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds.clone());
+        world.try_add(id, bounds.clone()).unwrap();
         world.remove(&id);
-        world.add(id, bounds)
+        world.try_add(id, bounds).unwrap()
     }
 
     #[test]
@@ -612,7 +1239,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(1, bounds);
+        world.try_add(1, bounds).unwrap();
         let removed = world.remove(&2);
         assert!(removed.is_none())
     }
@@ -634,7 +1261,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds.clone());
+        world.try_add(id, bounds.clone()).unwrap();
         let removed = world.remove(&id);
         assert_eq!(bounds, removed.unwrap());
         let removed = world.remove(&id);
@@ -644,7 +1271,7 @@ mod test {
     #[test]
     fn no_collisions() {
         let mut world = World::new(1000, 1000);
-        world.add(
+        world.try_add(
             1,
             Bounds {
                 x: 0,
@@ -652,8 +1279,8 @@ mod test {
                 width: 10,
                 height: 10,
             },
-        );
-        world.add(
+        ).unwrap();
+        world.try_add(
             2,
             Bounds {
                 x: 40,
@@ -661,7 +1288,7 @@ mod test {
                 width: 10,
                 height: 10,
             },
-        );
+        ).unwrap();
         world.query_intersects(|_, _| panic!());
     }
 
@@ -675,7 +1302,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_a, bounds_a.clone());
+        world.try_add(id_a, bounds_a.clone()).unwrap();
         let id_b = 2;
         let bounds_b = Bounds {
             x: 5,
@@ -683,7 +1310,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_b, bounds_b.clone());
+        world.try_add(id_b, bounds_b.clone()).unwrap();
 
         let mut collisions = Vec::<(CollisionObjectClone<i32>, CollisionObjectClone<i32>)>::new();
         world.query_intersects(|a, b| collisions.push((a.into(), b.into())));
@@ -707,14 +1334,14 @@ mod test {
             width: 10,
             height: 70,
         };
-        world.add(id_a, bounds_a.clone());
+        world.try_add(id_a, bounds_a.clone()).unwrap();
         let not_containing = Bounds {
             x: 54,
             y: 60,
             width: 3,
             height: 6,
         };
-        world.add(2, not_containing);
+        world.try_add(2, not_containing).unwrap();
         let id_b = 3;
         let bounds_b = Bounds {
             x: 0,
@@ -722,7 +1349,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_b, bounds_b.clone());
+        world.try_add(id_b, bounds_b.clone()).unwrap();
         let id_c = 4;
         let bounds_c = Bounds {
             x: 5,
@@ -730,7 +1357,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_c, bounds_c.clone());
+        world.try_add(id_c, bounds_c.clone()).unwrap();
         let mut collisions = Vec::<(CollisionObjectClone<i32>, CollisionObjectClone<i32>)>::new();
         world.query_intersects(|a, b| collisions.push((a.into(), b.into())));
         assert_eq!(3, collisions.len());
@@ -768,7 +1395,7 @@ mod test {
     #[test]
     fn no_collisions_id() {
         let mut world = World::new(1000, 1000);
-        world.add(
+        world.try_add(
             1,
             Bounds {
                 x: 0,
@@ -776,8 +1403,8 @@ mod test {
                 width: 10,
                 height: 10,
             },
-        );
-        world.add(
+        ).unwrap();
+        world.try_add(
             2,
             Bounds {
                 x: 40,
@@ -785,8 +1412,15 @@ mod test {
                 width: 10,
                 height: 10,
             },
-        );
-        world.query_intersects_id(&1, |_| panic!());
+        ).unwrap();
+        world.query_intersects_id(&1, |_| panic!()).unwrap();
+    }
+
+    #[test]
+    fn query_unknown_id() {
+        let world: World<u32> = World::new(1000, 1000);
+        assert_eq!(Err(Error::UnknownId), world.query_intersects_id(&1, |_| panic!()));
+        assert!(world.query_sweep_id(&1, &Vector::new(10, 0)).is_err());
     }
 
     #[test]
@@ -799,7 +1433,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_a, bounds_a.clone());
+        world.try_add(id_a, bounds_a.clone()).unwrap();
         let id_b = 2;
         let bounds_b = Bounds {
             x: 5,
@@ -807,10 +1441,10 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_b, bounds_b.clone());
+        world.try_add(id_b, bounds_b.clone()).unwrap();
 
         let mut collisions = Vec::<CollisionObjectClone<i32>>::new();
-        world.query_intersects_id(&id_a, |b| collisions.push(b.into()));
+        world.query_intersects_id(&id_a, |b| collisions.push(b.into())).unwrap();
 
         assert_eq!(1, collisions.len());
         let b = collisions.first().unwrap();
@@ -829,14 +1463,14 @@ mod test {
             width: 10,
             height: 70,
         };
-        world.add(id_a, bounds_a.clone());
+        world.try_add(id_a, bounds_a.clone()).unwrap();
         let not_containing = Bounds {
             x: 54,
             y: 60,
             width: 3,
             height: 6,
         };
-        world.add(2, not_containing);
+        world.try_add(2, not_containing).unwrap();
         let id_b = 3;
         let bounds_b = Bounds {
             x: 0,
@@ -844,7 +1478,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_b, bounds_b.clone());
+        world.try_add(id_b, bounds_b.clone()).unwrap();
         let id_c = 4;
         let bounds_c = Bounds {
             x: 5,
@@ -852,9 +1486,9 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_c, bounds_c.clone());
+        world.try_add(id_c, bounds_c.clone()).unwrap();
         let mut collisions = Vec::<CollisionObjectClone<i32>>::new();
-        world.query_intersects_id(&id_c, |b| collisions.push(b.into()));
+        world.query_intersects_id(&id_c, |b| collisions.push(b.into())).unwrap();
         assert_eq!(2, collisions.len());
         let a_c_pos = collisions
             .iter()
@@ -878,14 +1512,14 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds_a.clone());
+        world.try_add(id, bounds_a.clone()).unwrap();
         let bounds_b = Bounds {
             x: 40,
             y: 40,
             width: 10,
             height: 10,
         };
-        world.query_intersects_other(&bounds_b, |_| panic!());
+        world.query_intersects_other(&bounds_b, Shape::Aabb, &Layers::default(), |_| panic!());
     }
 
     #[test]
@@ -898,7 +1532,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds_a.clone());
+        world.try_add(id, bounds_a.clone()).unwrap();
         let bounds_b = Bounds {
             x: 5,
             y: 5,
@@ -906,7 +1540,7 @@ mod test {
             height: 10,
         };
         let mut collisions = Vec::new();
-        world.query_intersects_other(&bounds_b, |obj| {
+        world.query_intersects_other(&bounds_b, Shape::Aabb, &Layers::default(), |obj| {
             collisions.push((*obj.id, obj.bounds.clone()))
         });
         assert_eq!(1, collisions.len());
@@ -915,6 +1549,21 @@ mod test {
         assert_eq!(bounds_a, *coll_bounds);
     }
 
+    #[test]
+    fn collision_other_outside_arena() {
+        let mut world = World::new(1000, 1000);
+        world.try_add(1, square(995, 500, 10)).unwrap();
+        let mut collisions = Vec::new();
+        let outside = square(1005, 500, 20);
+        world.query_intersects_other(&outside, Shape::Aabb, &Layers::default(), |obj| {
+            collisions.push(*obj.id)
+        });
+        assert_eq!(vec![1], collisions);
+        let far_away = square(5000, -5000, 10);
+        world.query_intersects_other(&far_away, Shape::Aabb, &Layers::default(), |_| panic!());
+        world.query_contains_other(&far_away, Shape::Aabb, &Layers::default(), |_| panic!());
+    }
+
 
     #[test]
     fn multiple_collision_other() {
@@ -926,14 +1575,14 @@ mod test {
             width: 10,
             height: 70,
         };
-        world.add(id_a, bounds_a.clone());
+        world.try_add(id_a, bounds_a.clone()).unwrap();
         let not_containing = Bounds {
             x: 54,
             y: 60,
             width: 3,
             height: 6,
         };
-        world.add(2, not_containing);
+        world.try_add(2, not_containing).unwrap();
         let id_b = 3;
         let bounds_b = Bounds {
             x: 0,
@@ -941,7 +1590,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id_b, bounds_b.clone());
+        world.try_add(id_b, bounds_b.clone()).unwrap();
         let bounds_c = Bounds {
             x: 5,
             y: 5,
@@ -949,7 +1598,7 @@ mod test {
             height: 10,
         };
         let mut collisions = Vec::new();
-        world.query_intersects_other(&bounds_c, |obj| {
+        world.query_intersects_other(&bounds_c, Shape::Aabb, &Layers::default(), |obj| {
             collisions.push((*obj.id, obj.bounds.clone()))
         });
         assert_eq!(2, collisions.len());
@@ -972,14 +1621,14 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds_a.clone());
+        world.try_add(id, bounds_a.clone()).unwrap();
         let bounds_b = Bounds {
             x: 40,
             y: 40,
             width: 10,
             height: 10,
         };
-        world.query_contains_other(&bounds_b, |_| panic!());
+        world.query_contains_other(&bounds_b, Shape::Aabb, &Layers::default(), |_| panic!());
     }
 
     #[test]
@@ -992,7 +1641,7 @@ mod test {
             width: 10,
             height: 10,
         };
-        world.add(id, bounds_a.clone());
+        world.try_add(id, bounds_a.clone()).unwrap();
         let bounds_b = Bounds {
             x: 2,
             y: 2,
@@ -1000,7 +1649,7 @@ mod test {
             height: 3,
         };
         let mut containing = Vec::new();
-        world.query_contains_other(&bounds_b, |obj| {
+        world.query_contains_other(&bounds_b, Shape::Aabb, &Layers::default(), |obj| {
             containing.push((*obj.id, obj.bounds.clone()))
         });
         assert_eq!(1, containing.len());
@@ -1020,14 +1669,14 @@ mod test {
             width: 100,
             height: 100,
         };
-        world.add(id_a, bounds_a.clone());
+        world.try_add(id_a, bounds_a.clone()).unwrap();
         let not_containing = Bounds {
             x: 0,
             y: 0,
             width: 3,
             height: 6,
         };
-        world.add(2, not_containing);
+        world.try_add(2, not_containing).unwrap();
         let id_b = 3;
         let bounds_b = Bounds {
             x: 6,
@@ -1035,7 +1684,7 @@ mod test {
             width: 2,
             height: 2,
         };
-        world.add(id_b, bounds_b.clone());
+        world.try_add(id_b, bounds_b.clone()).unwrap();
         let bounds_c = Bounds {
             x: 5,
             y: 5,
@@ -1043,7 +1692,7 @@ mod test {
             height: 1,
         };
         let mut containing = Vec::new();
-        world.query_contains_other(&bounds_c, |obj| {
+        world.query_contains_other(&bounds_c, Shape::Aabb, &Layers::default(), |obj| {
             containing.push((*obj.id, obj.bounds.clone()))
         });
         assert_eq!(2, containing.len());
@@ -1055,4 +1704,514 @@ mod test {
         assert_eq!(id_b, coll_id);
         assert_eq!(bounds_b, *coll_bounds);
     }
+
+    #[test]
+    fn sweep_hit() {
+        let a = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let b = Bounds {
+            x: 20,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let (time, normal) = a.sweep(&Vector::new(20, 0), &b).unwrap();
+        assert_eq!(Fixed::from_f64(0.5), time);
+        assert_eq!(Vector::new(-1, 0), normal);
+    }
+
+    #[test]
+    fn sweep_hit_fractional() {
+        let a = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let b = Bounds {
+            x: 20,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let vel = Vector {
+            x: Fixed::from_f64(12.5),
+            y: Fixed::zero(),
+        };
+        let (time, normal) = a.sweep(&vel, &b).unwrap();
+        assert_eq!(Fixed::from(4) / 5, time);
+        assert_eq!(Vector::new(-1, 0), normal);
+    }
+
+    #[test]
+    fn sweep_miss() {
+        let a = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let b = Bounds {
+            x: 20,
+            y: 50,
+            width: 10,
+            height: 10,
+        };
+        assert!(a.sweep(&Vector::new(20, 0), &b).is_none());
+    }
+
+    #[test]
+    fn sweep_too_short() {
+        let a = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let b = Bounds {
+            x: 50,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        assert!(a.sweep(&Vector::new(20, 0), &b).is_none());
+    }
+
+    #[test]
+    fn sweep_moving_away() {
+        let a = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let b = Bounds {
+            x: 10,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        assert!(a.sweep(&Vector::new(-20, 0), &b).is_none());
+    }
+
+    #[test]
+    fn sweep_vertical_normal() {
+        let a = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let b = Bounds {
+            x: 2,
+            y: -30,
+            width: 10,
+            height: 10,
+        };
+        let (time, normal) = a.sweep(&Vector::new(1, -40), &b).unwrap();
+        assert_eq!(Fixed::from_f64(0.5), time);
+        assert_eq!(Vector::new(0, 1), normal);
+    }
+
+    #[test]
+    fn sweep_through_thin_bounds() {
+        let ball = Bounds {
+            x: 500,
+            y: 500,
+            width: 15,
+            height: 15,
+        };
+        let paddle = Bounds {
+            x: 530,
+            y: 500,
+            width: 15,
+            height: 75,
+        };
+        let vel = Vector::new(50, 0);
+        let end = Bounds {
+            x: ball.x + vel.x.round(),
+            ..ball.clone()
+        };
+        assert!(!end.intersects(&paddle));
+        let (time, normal) = ball.sweep(&vel, &paddle).unwrap();
+        assert_eq!(Fixed::from(3) / 10, time);
+        assert_eq!(Vector::new(-1, 0), normal);
+    }
+
+    #[test]
+    fn no_sweep_id() {
+        let mut world = World::new(1000, 1000);
+        world.try_add(
+            1,
+            Bounds {
+                x: 50,
+                y: 50,
+                width: 10,
+                height: 10,
+            },
+        ).unwrap();
+        world.try_add(
+            2,
+            Bounds {
+                x: 50,
+                y: 150,
+                width: 10,
+                height: 10,
+            },
+        ).unwrap();
+        assert!(world.query_sweep_id(&1, &Vector::new(40, 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn sweep_id_earliest() {
+        let mut world = World::new(1000, 1000);
+        world.try_add(
+            1,
+            Bounds {
+                x: 150,
+                y: 150,
+                width: 10,
+                height: 10,
+            },
+        ).unwrap();
+        world.try_add(
+            2,
+            Bounds {
+                x: 210,
+                y: 150,
+                width: 10,
+                height: 10,
+            },
+        ).unwrap();
+        world.try_add(
+            3,
+            Bounds {
+                x: 180,
+                y: 150,
+                width: 10,
+                height: 10,
+            },
+        ).unwrap();
+        let sweep = world.query_sweep_id(&1, &Vector::new(80, 0)).unwrap().unwrap();
+        assert_eq!(3, *sweep.id);
+        assert_eq!(Fixed::from_f64(0.25), sweep.time);
+        assert_eq!(Vector::new(-1, 0), sweep.normal);
+    }
+
+    #[test]
+    fn sweep_other_across_cells() {
+        let mut world = World::new(1000, 1000);
+        let id = 1;
+        let bounds = Bounds {
+            x: 450,
+            y: 500,
+            width: 10,
+            height: 10,
+        };
+        world.try_add(id, bounds.clone()).unwrap();
+        let moving = Bounds {
+            x: 50,
+            y: 500,
+            width: 10,
+            height: 10,
+        };
+        let sweep = world
+            .query_sweep_other(&moving, Shape::Aabb, &Layers::default(), &Vector::new(800, 0))
+            .unwrap();
+        assert_eq!(id, *sweep.id);
+        assert_eq!(bounds, *sweep.bounds);
+        assert_eq!(Fixed::from(39) / 80, sweep.time);
+    }
+
+    #[test]
+    fn query_intersects_stable_order() {
+        let build = || {
+            let mut world = World::new(1000, 1000);
+            for i in 0..20 {
+                world.try_add(
+                    i,
+                    Bounds {
+                        x: (i % 5) * 95,
+                        y: (i / 5) * 95,
+                        width: 100,
+                        height: 100,
+                    },
+                ).unwrap();
+            }
+            let mut collisions = Vec::new();
+            world.query_intersects(|a, b| collisions.push((*a.id, *b.id)));
+            collisions
+        };
+        let first = build();
+        assert!(!first.is_empty());
+        for _ in 0..10 {
+            assert_eq!(first, build());
+        }
+    }
+
+    const BALL: u32 = 1;
+    const PADDLE: u32 = 1 << 1;
+
+    #[test]
+    fn layers_collide_both_ways() {
+        let ball = Layers::new(BALL, PADDLE);
+        let paddle = Layers::new(PADDLE, BALL);
+        assert!(ball.collides(&paddle));
+        assert!(paddle.collides(&ball));
+        assert!(!paddle.collides(&paddle));
+        assert!(!ball.collides(&Layers::new(PADDLE, PADDLE)));
+        assert!(Layers::default().collides(&ball));
+    }
+
+    #[test]
+    fn query_intersects_filters_layers() {
+        let mut world = World::new(1000, 1000);
+        let at = |x| {
+            Bounds {
+                x,
+                y: 100,
+                width: 10,
+                height: 10,
+            }
+        };
+        world
+            .try_add_with(1, at(100), Shape::Aabb, Layers::new(PADDLE, BALL))
+            .unwrap();
+        world
+            .try_add_with(2, at(105), Shape::Aabb, Layers::new(PADDLE, BALL))
+            .unwrap();
+        world.query_intersects(|_, _| panic!());
+        world
+            .try_add_with(3, at(102), Shape::Aabb, Layers::new(BALL, PADDLE))
+            .unwrap();
+        let mut collisions = Vec::new();
+        world.query_intersects(|a, b| collisions.push((*a.id, *b.id)));
+        assert_eq!(2, collisions.len());
+        assert!(collisions.iter().all(|&(a, b)| a == 3 || b == 3));
+        let mut hits = Vec::new();
+        world.query_intersects_id(&1, |other| hits.push(*other.id)).unwrap();
+        assert_eq!(vec![3], hits);
+    }
+
+    #[test]
+    fn sweep_skips_other_layers() {
+        let mut world = World::new(1000, 1000);
+        let bounds = |x| {
+            Bounds {
+                x,
+                y: 500,
+                width: 10,
+                height: 10,
+            }
+        };
+        world
+            .try_add_with(1, bounds(100), Shape::Aabb, Layers::new(BALL, PADDLE))
+            .unwrap();
+        world
+            .try_add_with(2, bounds(200), Shape::Aabb, Layers::new(BALL, PADDLE))
+            .unwrap();
+        world
+            .try_add_with(3, bounds(300), Shape::Aabb, Layers::new(PADDLE, BALL))
+            .unwrap();
+        let sweep = world.query_sweep_id(&1, &Vector::new(300, 0)).unwrap().unwrap();
+        assert_eq!(3, *sweep.id);
+        let ball = Layers::new(BALL, PADDLE);
+        let sweep = world.query_sweep_other(
+            &bounds(150),
+            Shape::Aabb,
+            &ball,
+            &Vector::new(300, 0),
+        );
+        assert_eq!(3, *sweep.unwrap().id);
+    }
+
+    #[test]
+    fn remove_forgets_layers() {
+        let mut world = World::new(1000, 1000);
+        let bounds = Bounds {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        world
+            .try_add_with(1, bounds.clone(), Shape::Aabb, Layers::new(BALL, PADDLE))
+            .unwrap();
+        world.remove(&1);
+        assert_eq!(None, world.get_layers(&1));
+        world.try_add(1, bounds).unwrap();
+        assert_eq!(Some(&Layers::default()), world.get_layers(&1));
+    }
+
+    fn square(x: i32, y: i32, size: i32) -> Bounds {
+        Bounds {
+            x,
+            y,
+            width: size,
+            height: size,
+        }
+    }
+
+    #[test]
+    fn aabb_contact() {
+        let contact = square(0, 0, 10)
+            .contact(Shape::Aabb, &square(8, 3, 10), Shape::Aabb)
+            .unwrap();
+        assert_eq!(Vector::new(-1, 0), contact.normal);
+        assert_eq!(Fixed::from(2), contact.penetration);
+    }
+
+    #[test]
+    fn circle_contact() {
+        let a = square(100, 100, 10);
+        let contact = a.contact(Shape::Circle, &square(106, 100, 10), Shape::Circle)
+            .unwrap();
+        assert_eq!(Vector::new(-1, 0), contact.normal);
+        assert_eq!(Fixed::from(4), contact.penetration);
+        // Their boxes would overlap
+        let b = square(108, 108, 10);
+        assert!(a.intersects(&b));
+        assert_eq!(None, a.contact(Shape::Circle, &b, Shape::Circle));
+    }
+
+    #[test]
+    fn circle_misses_box_corner() {
+        let circle = square(100, 100, 10);
+        let aabb = square(109, 109, 10);
+        assert!(circle.contact(Shape::Aabb, &aabb, Shape::Aabb).is_some());
+        assert_eq!(None, circle.contact(Shape::Circle, &aabb, Shape::Aabb));
+        assert_eq!(None, aabb.contact(Shape::Aabb, &circle, Shape::Circle));
+    }
+
+    #[test]
+    fn circle_aabb_contact() {
+        let circle = square(100, 100, 10);
+        let aabb = square(108, 100, 10);
+        let contact = circle.contact(Shape::Circle, &aabb, Shape::Aabb).unwrap();
+        assert_eq!(Vector::new(-1, 0), contact.normal);
+        assert_eq!(Fixed::from(2), contact.penetration);
+        let contact = aabb.contact(Shape::Aabb, &circle, Shape::Circle).unwrap();
+        assert_eq!(Vector::new(1, 0), contact.normal);
+    }
+
+    #[test]
+    fn circle_inside_box_leaves_through_closest_edge() {
+        let circle = square(100, 100, 10);
+        let contact = circle
+            .contact(Shape::Circle, &square(110, 100, 40), Shape::Aabb)
+            .unwrap();
+        assert_eq!(Vector::new(-1, 0), contact.normal);
+        assert_eq!(Fixed::from(15), contact.penetration);
+    }
+
+    #[test]
+    fn contains_shape() {
+        let circle = square(500, 500, 100);
+        assert!(circle.contains_shape(Shape::Circle, &square(520, 520, 20), Shape::Aabb));
+        assert!(!circle.contains_shape(Shape::Circle, &square(530, 530, 20), Shape::Aabb));
+        assert!(circle.contains_shape(Shape::Circle, &square(535, 500, 20), Shape::Circle));
+        assert!(!circle.contains_shape(Shape::Circle, &square(545, 500, 20), Shape::Circle));
+        assert!(!circle.contains_shape(Shape::Aabb, &square(545, 500, 20), Shape::Circle));
+        assert!(circle.contains_shape(Shape::Aabb, &square(535, 500, 20), Shape::Circle));
+    }
+
+    #[test]
+    fn sweep_circle_past_corner() {
+        let circle = square(100, 100, 10);
+        let vel = Vector::new(30, 30);
+        let aabb = square(120, 102, 10);
+        assert!(circle.sweep(&vel, &aabb).is_some());
+        assert_eq!(None, circle.sweep_shape(Shape::Circle, &vel, &aabb, Shape::Aabb));
+        let aabb = square(120, 104, 10);
+        let (time, normal) = circle
+            .sweep_shape(Shape::Circle, &vel, &aabb, Shape::Aabb)
+            .unwrap();
+        assert!(time > Fixed::from(1) / 3 && time < Fixed::from(1));
+        assert!(normal.x < Fixed::zero() && normal.y > Fixed::zero());
+    }
+
+    #[test]
+    fn sweep_circles() {
+        let vel = Vector::new(30, 0);
+        let other = square(130, 100, 10);
+        for &shape in &[Shape::Circle, Shape::Aabb] {
+            let (time, normal) = square(100, 100, 10)
+                .sweep_shape(shape, &vel, &other, Shape::Circle)
+                .unwrap();
+            assert_eq!(Fixed::from(2) / 3, time);
+            assert_eq!(Vector::new(-1, 0), normal);
+        }
+    }
+
+    #[test]
+    fn query_reports_contact() {
+        let mut world = World::new(1000, 1000);
+        world
+            .try_add_with(1, square(100, 100, 10), Shape::Circle, Layers::default())
+            .unwrap();
+        world.try_add(2, square(108, 100, 10)).unwrap();
+        let mut contacts = Vec::new();
+        world.query_intersects_id(&1, |other| contacts.push(other.contact.unwrap())).unwrap();
+        assert_eq!(1, contacts.len());
+        assert_eq!(Vector::new(-1, 0), contacts[0].normal);
+        assert_eq!(Fixed::from(2), contacts[0].penetration);
+        let mut pairs = Vec::new();
+        world.query_intersects(|a, b| pairs.push((a.contact.unwrap(), b.contact.unwrap())));
+        assert_eq!(1, pairs.len());
+        let (ref a, ref b) = pairs[0];
+        assert_eq!(Vector::new(1, 0), a.normal);
+        assert_eq!(Vector::new(-1, 0), b.normal);
+    }
+
+    #[test]
+    fn history_rewind() {
+        let mut world = World::new(1000, 1000);
+        let mut history = History::new(10);
+        world.try_add(
+            1,
+            Bounds {
+                x: 100,
+                y: 100,
+                width: 10,
+                height: 10,
+            },
+        ).unwrap();
+        for i in 0..3 {
+            world.try_place(&1, &Pos::from(Vector::new(100 + i * 10, 100))).unwrap();
+            history.push(&world, &[1, 2]);
+        }
+        assert_eq!(3, history.len());
+        assert_eq!(120, history.rewind(0, &1).unwrap().bounds.x);
+        assert_eq!(100, history.rewind(2, &1).unwrap().bounds.x);
+        assert_eq!(Shape::default(), history.rewind(2, &1).unwrap().shape);
+        assert!(history.rewind(3, &1).is_none());
+        // Never added to the world
+        assert!(history.rewind(0, &2).is_none());
+    }
+
+    #[test]
+    fn history_capacity() {
+        let mut world = World::new(1000, 1000);
+        let mut history = History::new(2);
+        world.try_add(
+            1,
+            Bounds {
+                x: 100,
+                y: 100,
+                width: 10,
+                height: 10,
+            },
+        ).unwrap();
+        for i in 0..5 {
+            world.try_place(&1, &Pos::from(Vector::new(100 + i * 10, 100))).unwrap();
+            history.push(&world, &[1]);
+        }
+        assert_eq!(2, history.len());
+        assert_eq!(130, history.rewind(1, &1).unwrap().bounds.x);
+        assert!(history.rewind(2, &1).is_none());
+    }
 }
@@ -0,0 +1,347 @@
+extern crate serde_json;
+
+use model::game::Vector;
+use model::fixed::Fixed;
+use room::RoomConfig;
+use util::{override_from_env, parse_env_var};
+use error::Error;
+
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Upper bound for sizes and speeds, keeps positions and velocities far enough from the
+/// limits of `Fixed` that adding them up can't overflow
+pub const MAX_UNITS: i32 = 10_000;
+
+/// Sizes and speeds of everything in the arena, shared by all systems as a resource.
+/// Speeds are in units per second, so they don't depend on the tick rate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameConfig {
+    pub arena: ArenaConfig,
+    pub paddle: PaddleConfig,
+    pub ball: BallConfig,
+    /// Edge length of a cell in the collision grid
+    pub cell_size: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArenaConfig {
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaddleConfig {
+    pub width: i32,
+    pub height: i32,
+    /// Vertical speed while a move command is active
+    pub speed: Fixed,
+    /// Distance between the center of a paddle and its edge of the arena
+    pub inset: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BallConfig {
+    pub size: i32,
+    /// Velocity of a ball served towards the right side
+    pub serve: Vector,
+    /// Degrees the ball leaves a paddle at when it hits the very edge, the center sends it
+    /// straight back
+    pub max_bounce_angle: Fixed,
+    /// Share of the paddle's vertical velocity the ball picks up when hit
+    pub spin: Fixed,
+    /// Added to the speed of the ball whenever a paddle hits it
+    pub speed_up: Fixed,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            arena: ArenaConfig::default(),
+            paddle: PaddleConfig::default(),
+            ball: BallConfig::default(),
+            cell_size: 100,
+        }
+    }
+}
+
+impl Default for ArenaConfig {
+    fn default() -> Self {
+        ArenaConfig {
+            width: 1000,
+            height: 1000,
+        }
+    }
+}
+
+impl Default for PaddleConfig {
+    fn default() -> Self {
+        PaddleConfig {
+            width: 15,
+            height: 75,
+            speed: Fixed::from(750),
+            inset: 20,
+        }
+    }
+}
+
+impl Default for BallConfig {
+    fn default() -> Self {
+        BallConfig {
+            size: 15,
+            serve: Vector::new(210, 330),
+            max_bounce_angle: Fixed::from(60),
+            spin: Fixed::from_f64(0.25),
+            speed_up: Fixed::from(30),
+        }
+    }
+}
+
+impl ArenaConfig {
+    pub fn center(&self) -> Vector {
+        Vector::new(self.width / 2, self.height / 2)
+    }
+}
+
+impl GameConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let arena = &self.arena;
+        let paddle = &self.paddle;
+        let ball = &self.ball;
+        let checks = [
+            (arena.width > 0 && arena.height > 0, "arena has to have a size"),
+            (
+                arena.width <= MAX_UNITS && arena.height <= MAX_UNITS,
+                "arena must not be larger than 10000",
+            ),
+            (
+                self.cell_size > 0 && self.cell_size <= arena.width.min(arena.height),
+                "cell_size has to fit into the arena",
+            ),
+            (
+                paddle.width > 0 && paddle.height > 0 && paddle.height < arena.height,
+                "paddle has to fit into the arena",
+            ),
+            (
+                paddle.inset >= paddle.width / 2 && paddle.inset * 2 < arena.width,
+                "paddle.inset has to keep both paddles inside the arena",
+            ),
+            (paddle.speed > Fixed::zero(), "paddle.speed has to be positive"),
+            (
+                paddle.speed <= Fixed::from(MAX_UNITS),
+                "paddle.speed must not be larger than 10000",
+            ),
+            (
+                ball.size > 0 && ball.size < arena.width.min(arena.height),
+                "ball has to fit into the arena",
+            ),
+            (
+                ball.serve.x != Fixed::zero(),
+                "ball.serve.x must not be 0, or nobody could score",
+            ),
+            (
+                ball.serve.x.abs() <= Fixed::from(MAX_UNITS) &&
+                    ball.serve.y.abs() <= Fixed::from(MAX_UNITS),
+                "ball.serve must not be faster than 10000",
+            ),
+            (
+                ball.max_bounce_angle >= Fixed::zero() && ball.max_bounce_angle < Fixed::from(90),
+                "ball.max_bounce_angle has to be between 0 and 90 degrees",
+            ),
+            (
+                ball.spin >= Fixed::zero() && ball.spin <= Fixed::from(1),
+                "ball.spin has to be between 0 and 1",
+            ),
+            (
+                ball.speed_up >= Fixed::zero() && ball.speed_up <= Fixed::from(MAX_UNITS),
+                "ball.speed_up has to be between 0 and 10000",
+            ),
+        ];
+        check_all(&checks)
+    }
+}
+
+/// Everything the server can be configured with.
+/// Read from the JSON file at CORE_CONFIG, environmental variables take precedence.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub port: u32,
+    /// Used for every room
+    pub room: RoomConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 8081,
+            room: RoomConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Panics on invalid config, there is no point in starting with one
+    pub fn load() -> Self {
+        let mut config = match env::var_os("CORE_CONFIG") {
+            Some(path) => Config::read(Path::new(&path)).unwrap_or_else(|err| {
+                panic!("Failed to read config {:?}: {}", path, err)
+            }),
+            None => Config::default(),
+        };
+        if let Err(err) = config.override_from_env() {
+            panic!("Invalid config: {}", err);
+        }
+        if let Err(err) = config.validate() {
+            panic!("Invalid config: {}", err);
+        }
+        config
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| err.to_string())?;
+        serde_json::from_reader(file).map_err(|err| err.to_string())
+    }
+
+    pub fn override_from_env(&mut self) -> Result<(), Error> {
+        let room = &mut self.room;
+        override_from_env("CORE_PORT", &mut self.port)?;
+        override_from_env("CORE_UPDATES_PER_SEC", &mut room.updates_per_sec)?;
+        override_from_env("CORE_SENDS_PER_SEC", &mut room.sends_per_sec)?;
+        override_from_env("CORE_SCORE_LIMIT", &mut room.score_limit)?;
+        override_from_env("CORE_COUNTDOWN_SECS", &mut room.countdown_secs)?;
+        override_from_env("CORE_GAME_OVER_SECS", &mut room.game_over_secs)?;
+        override_from_env("CORE_BOT_TIMEOUT_SECS", &mut room.bot_timeout_secs)?;
+        // Ticks are what the bots count, but milliseconds are easier to reason about
+        if let Some(bot_reaction_ms) = parse_env_var::<u64>("CORE_BOT_REACTION_MS")? {
+            room.bot_difficulty.reaction_ticks =
+                (bot_reaction_ms * room.updates_per_sec / 1000) as u32;
+        }
+        override_from_env(
+            "CORE_BOT_PREDICTION_ERROR",
+            &mut room.bot_difficulty.prediction_error,
+        )?;
+        override_from_env("CORE_BOT_MAX_SPEED", &mut room.bot_difficulty.max_speed)?;
+        override_from_env("CORE_OUTBOX_CAPACITY", &mut room.outbox_capacity)?;
+        override_from_env("CORE_MAX_REWIND_MS", &mut room.max_rewind_ms)?;
+        override_from_env("CORE_SESSION_GRACE_SECS", &mut room.session_grace_secs)?;
+        override_from_env("CORE_MAX_MESSAGE_BYTES", &mut room.max_message_bytes)?;
+        override_from_env("CORE_MAX_MESSAGES_PER_SEC", &mut room.max_messages_per_sec)?;
+        override_from_env("CORE_MAX_STRIKES", &mut room.max_strikes)?;
+        if let Some(seed) = parse_env_var("CORE_SEED")? {
+            room.seed = Some(seed);
+        }
+        if let Some(replay_dir) = env::var_os("CORE_REPLAY_DIR") {
+            room.replay_dir = Some(PathBuf::from(replay_dir));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let room = &self.room;
+        let difficulty = &room.bot_difficulty;
+        let checks = [
+            (
+                room.updates_per_sec > 0 && room.updates_per_sec <= 1000,
+                "updates_per_sec has to be between 1 and 1000",
+            ),
+            (
+                room.sends_per_sec > 0 && room.sends_per_sec <= room.updates_per_sec,
+                "sends_per_sec has to be between 1 and updates_per_sec",
+            ),
+            (room.score_limit > 0, "score_limit has to be positive"),
+            (room.outbox_capacity > 0, "outbox_capacity has to be positive"),
+            (room.max_message_bytes > 0, "max_message_bytes has to be positive"),
+            (room.max_messages_per_sec > 0, "max_messages_per_sec has to be positive"),
+            (room.max_strikes > 0, "max_strikes has to be positive"),
+            (
+                difficulty.max_speed > Fixed::zero() &&
+                    difficulty.max_speed <= Fixed::from(MAX_UNITS),
+                "bot_difficulty.max_speed has to be between 0 and 10000",
+            ),
+            (
+                difficulty.prediction_error >= 0 && difficulty.prediction_error <= MAX_UNITS,
+                "bot_difficulty.prediction_error has to be between 0 and 10000",
+            ),
+        ];
+        check_all(&checks)?;
+        room.game.validate()
+    }
+}
+
+fn check_all(checks: &[(bool, &str)]) -> Result<(), String> {
+    match checks.iter().find(|&&(is_valid, _)| !is_valid) {
+        Some(&(_, reason)) => Err(reason.to_string()),
+        None => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(Ok(()), Config::default().validate());
+    }
+
+    #[test]
+    fn missing_fields_are_defaulted() {
+        let json = r#"{"room": {"score_limit": 3, "game": {"paddle": {"height": 100}}}}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(3, config.room.score_limit);
+        assert_eq!(100, config.room.game.paddle.height);
+        assert_eq!(15, config.room.game.paddle.width);
+        assert_eq!(30, config.room.updates_per_sec);
+    }
+
+    #[test]
+    fn speeds_can_have_fractions() {
+        let json = r#"{"room": {"game": {"paddle": {"speed": 12.5}}}}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(Fixed::from_f64(12.5), config.room.game.paddle.speed);
+        let json = r#"{"room": {"game": {"ball": {"serve": {"x": 3.25, "y": 4}}}}}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(Fixed::from_f64(3.25), config.room.game.ball.serve.x);
+        assert_eq!(Fixed::from(4), config.room.game.ball.serve.y);
+    }
+
+    #[test]
+    fn sending_faster_than_updating_is_invalid() {
+        let mut config = Config::default();
+        config.room.updates_per_sec = 60;
+        config.room.sends_per_sec = 20;
+        assert_eq!(Ok(()), config.validate());
+        config.room.sends_per_sec = 61;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn paddle_taller_than_arena_is_invalid() {
+        let mut config = Config::default();
+        config.room.game.paddle.height = config.room.game.arena.height;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn sizes_and_speeds_are_bounded() {
+        let mut config = Config::default();
+        config.room.game.arena.width = 70_000;
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.room.game.paddle.speed = Fixed::from(MAX_UNITS + 1);
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.room.game.ball.serve.y = Fixed::from(-MAX_UNITS - 1);
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.room.game.ball.speed_up = Fixed::from(MAX_UNITS);
+        assert_eq!(Ok(()), config.validate());
+    }
+}
@@ -0,0 +1,74 @@
+use std::any::Any;
+use std::error;
+use std::fmt;
+
+/// Everything that can go wrong without having to take the whole server down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    MissingEnvVar(String),
+    InvalidEnvVar(String),
+    /// The collision world doesn't know the id
+    UnknownId,
+    /// The collision world already knows the id
+    DuplicateId,
+    /// The bounds don't overlap with the collision world
+    OutOfBounds,
+    /// An entity lacks a component its kind of actor always has
+    MissingComponent(String),
+    /// A message couldn't be turned into something we can send
+    Encode(String),
+    /// The tick loop of a room panicked
+    Crashed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MissingEnvVar(ref var) => {
+                write!(
+                    f,
+                    "{} must be specified. Did you forget to add it to your .env file?",
+                    var
+                )
+            }
+            Error::InvalidEnvVar(ref var) => {
+                write!(f, "Failed to parse environmental variable {}", var)
+            }
+            Error::UnknownId => write!(f, "Id doesn't exist"),
+            Error::DuplicateId => write!(f, "Id already registered"),
+            Error::OutOfBounds => write!(f, "Bounds lie outside of the world"),
+            Error::MissingComponent(ref name) => write!(f, "{} component missing", name),
+            Error::Encode(ref reason) => write!(f, "Failed to encode message: {}", reason),
+            Error::Crashed(ref reason) => write!(f, "Panicked: {}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::MissingEnvVar(_) => "missing environmental variable",
+            Error::InvalidEnvVar(_) => "invalid environmental variable",
+            Error::UnknownId => "unknown id",
+            Error::DuplicateId => "duplicate id",
+            Error::OutOfBounds => "out of bounds",
+            Error::MissingComponent(_) => "missing component",
+            Error::Encode(_) => "failed to encode message",
+            Error::Crashed(_) => "crashed",
+        }
+    }
+}
+
+impl Error {
+    /// Keeps the message of a caught panic, if it has one
+    pub fn from_panic(payload: &(Any + Send)) -> Self {
+        let reason = if let Some(reason) = payload.downcast_ref::<String>() {
+            reason.clone()
+        } else if let Some(reason) = payload.downcast_ref::<&'static str>() {
+            reason.to_string()
+        } else {
+            "Unknown reason".to_string()
+        };
+        Error::Crashed(reason)
+    }
+}
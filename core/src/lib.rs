@@ -2,7 +2,6 @@
 
 #[macro_use]
 extern crate serde_derive;
-#[macro_use]
 extern crate serde_json;
 
 #[macro_use]
@@ -11,14 +10,18 @@ extern crate specs;
 
 #[macro_use]
 extern crate derive_more;
-#[macro_use]
-extern crate maplit;
 
 
 #[macro_use]
 pub mod util;
+pub mod error;
 pub mod model;
 
 pub mod system;
 pub mod bootstrap;
 pub mod collision;
+pub mod room;
+pub mod config;
+pub mod outbox;
+pub mod replay;
+pub mod simulation;
@@ -1,10 +1,12 @@
 extern crate specs;
-extern crate websocket_server;
 use self::specs::{Component, DenseVecStorage};
-use self::websocket_server::SendChannel;
 
-use super::game::{Vector, Id as GameId};
-use model::network::Command;
+use super::game::{Vector, Id as GameId, Side, SessionToken};
+use super::fixed::Fixed;
+use model::network::{Command, Format, Latency, serialize_id};
+use util::SeqId;
+use outbox::Outbox;
+use collision::{Layers, Shape};
 use std::ops::{Deref, DerefMut};
 use std::convert::From;
 use std::collections::HashMap;
@@ -17,21 +19,135 @@ vectype!(Pos);
 #[derive(Debug, Clone, Serialize, Component)]
 pub struct Bounciness {}
 
-newtype!(Friction(i32): Debug, Clone, Serialize, Component);
+newtype!(Friction(Fixed): Debug, Clone, Serialize, Component);
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ActorKind {
     Player,
     Ball,
+    Bot,
+}
+
+const BALL_LAYER: u32 = 1;
+const PADDLE_LAYER: u32 = 1 << 1;
+
+impl ActorKind {
+    /// Balls and paddles only care about each other, walls are handled by Bounce
+    pub fn layers(&self) -> Layers {
+        match *self {
+            ActorKind::Ball => Layers::new(BALL_LAYER, PADDLE_LAYER),
+            ActorKind::Player | ActorKind::Bot => Layers::new(PADDLE_LAYER, BALL_LAYER),
+        }
+    }
+
+    pub fn shape(&self) -> Shape {
+        match *self {
+            ActorKind::Ball => Shape::Circle,
+            ActorKind::Player | ActorKind::Bot => Shape::Aabb,
+        }
+    }
 }
 
 
 #[derive(Debug, Clone, Serialize, Component)]
 pub struct Actor {
+    #[serde(serialize_with = "serialize_id")]
     pub id: GameId,
     pub kind: ActorKind,
 }
 
+#[derive(Debug, Clone, Component)]
+pub struct Paddle {
+    pub side: Side,
+    /// Vertical speed while a move command is active
+    pub speed: Fixed,
+    /// Ticks between the state its player was looking at and their latest input taking effect
+    pub lag_ticks: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Difficulty {
+    /// Ticks between looking at the ball
+    pub reaction_ticks: u32,
+    /// Maximum distance the predicted impact point is off by
+    pub prediction_error: i32,
+    /// In units per second
+    pub max_speed: Fixed,
+}
+
+impl Default for Difficulty {
+    fn default() -> Self {
+        Difficulty {
+            reaction_ticks: 6,
+            prediction_error: 40,
+            max_speed: Fixed::from(450),
+        }
+    }
+}
+
+/// Paddle steered by the server
+#[derive(Debug, Clone, Component)]
+pub struct Bot {
+    pub difficulty: Difficulty,
+    pub target_y: Option<Fixed>,
+    pub ticks_until_reaction: u32,
+    pub pressed: Option<Command>,
+    pub last_input: u32,
+    seed: u32,
+}
+
+impl Bot {
+    pub fn new(difficulty: Difficulty, id: &GameId) -> Self {
+        let bytes = id.as_bytes();
+        let seed = (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 |
+            bytes[3] as u32;
+        Bot {
+            difficulty,
+            target_y: None,
+            ticks_until_reaction: 0,
+            pressed: None,
+            last_input: 0,
+            // Xorshift gets stuck on zero
+            seed: if seed == 0 { 1 } else { seed },
+        }
+    }
+
+    /// Random offset within the prediction error
+    pub fn miss(&mut self) -> i32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        let range = self.difficulty.prediction_error * 2 + 1;
+        (self.seed % range as u32) as i32 - self.difficulty.prediction_error
+    }
+}
+
+/// Connected player that didn't get a paddle
+#[derive(Component)]
+pub struct Spectator;
+/// Former spectator that just got a paddle
+#[derive(Component)]
+pub struct Promoted;
+/// Player whose connection dropped, keeping its place until it resumes its session
+#[derive(Component)]
+pub struct Suspended {
+    /// Carried over to the resumed player, so reconnecting doesn't wipe the slate clean
+    pub strikes: u32,
+}
+/// Player that just resumed its session on a new connection
+#[derive(Component)]
+pub struct Resumed;
+
+/// Swept collision found by `Physics`, resolved by `Bounce`
+#[derive(Debug, Clone, Component)]
+pub struct Collision {
+    pub other: GameId,
+    pub normal: Vector,
+    /// Center of the other actor when we hit it, which might lie in the past
+    pub other_pos: Vector,
+}
+
 #[derive(Component)]
 pub struct ToSpawn;
 #[derive(Component)]
@@ -41,17 +157,30 @@ pub struct ToDespawn;
 pub type KeyboardState = HashMap<Command, bool>;
 #[derive(Component)]
 pub struct Player {
-    pub send_channel: SendChannel,
+    pub outbox: Outbox,
+    pub format: Format,
     pub inputs: Vec<KeyboardState>,
     pub last_input: u32,
+    /// Latest snapshot the client confirmed, used as the baseline for deltas
+    pub acked_tick: Option<SeqId>,
+    pub latency: Latency,
+    /// Lets the client come back as the same player after its connection dropped
+    pub session: SessionToken,
+    /// Offenses against the rules, too many get the client kicked
+    pub strikes: u32,
 }
 
 impl Player {
-    pub fn new(send_channel: SendChannel) -> Self {
+    pub fn new(outbox: Outbox, format: Format, session: SessionToken) -> Self {
         Player {
-            send_channel,
+            outbox,
+            format,
+            session,
             inputs: Vec::new(),
             last_input: 0,
+            acked_tick: None,
+            latency: Latency::default(),
+            strikes: 0,
         }
     }
 }
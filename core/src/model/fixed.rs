@@ -0,0 +1,501 @@
+extern crate serde;
+
+use self::serde::{Serialize, Serializer, Deserialize, Deserializer};
+use std::ops::{Add, AddAssign, Sub, SubAssign, Neg, Mul, Div, Rem};
+use std::str::FromStr;
+use std::fmt;
+
+const FRAC_BITS: u32 = 16;
+const ONE: i32 = 1 << FRAC_BITS;
+
+/// Sine of every whole degree from 0 to 90.
+/// Looking it up gives the same result everywhere, unlike the float functions of the platform.
+const SIN_TABLE: [i32; 91] = [
+    0, 1144, 2287, 3430, 4572, 5712, 6850, 7987,
+    9121, 10252, 11380, 12505, 13626, 14742, 15855, 16962,
+    18064, 19161, 20252, 21336, 22415, 23486, 24550, 25607,
+    26656, 27697, 28729, 29753, 30767, 31772, 32768, 33754,
+    34729, 35693, 36647, 37590, 38521, 39441, 40348, 41243,
+    42126, 42995, 43852, 44695, 45525, 46341, 47143, 47930,
+    48703, 49461, 50203, 50931, 51643, 52339, 53020, 53684,
+    54332, 54963, 55578, 56175, 56756, 57319, 57865, 58393,
+    58903, 59396, 59870, 60326, 60764, 61183, 61584, 61966,
+    62328, 62672, 62997, 63303, 63589, 63856, 64104, 64332,
+    64540, 64729, 64898, 65048, 65177, 65287, 65376, 65446,
+    65496, 65526, 65536,
+];
+
+/// Number with 16 integer and 16 fractional bits.
+/// Lets things move by fractions of a world unit, while every machine still computes
+/// exactly the same results. Serialized as a regular number, so clients never notice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    pub fn zero() -> Self {
+        Fixed(0)
+    }
+
+    pub fn from_bits(bits: i32) -> Self {
+        Fixed(bits)
+    }
+
+    pub fn to_bits(self) -> i32 {
+        self.0
+    }
+
+    /// Fails if the integer bits can't hold the value
+    pub fn checked_from(val: i32) -> Option<Self> {
+        let max = i32::max_value() >> FRAC_BITS;
+        if val >= -max - 1 && val <= max {
+            Some(Fixed(val << FRAC_BITS))
+        } else {
+            None
+        }
+    }
+
+    /// Rounds to the closest representable value, saturating at the limits
+    pub fn from_f64(val: f64) -> Self {
+        let bits = (val * f64::from(ONE)).round();
+        let bits = bits.max(f64::from(i32::min_value())).min(f64::from(i32::max_value()));
+        Fixed(bits as i32)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        f64::from(self.0) / f64::from(ONE)
+    }
+
+    /// Quotient of two numbers in the same unit, rounded down and saturating at the limits.
+    /// Dividing by 0 saturates in the direction of `num`.
+    pub fn ratio(num: i64, den: i64) -> Self {
+        if den == 0 {
+            return Fixed::saturated(num);
+        }
+        let (num, den) = if den < 0 { (-num, -den) } else { (num, den) };
+        let scaled = num << FRAC_BITS;
+        let mut bits = scaled / den;
+        if scaled % den != 0 && scaled < 0 {
+            bits -= 1;
+        }
+        Fixed::clamped(bits)
+    }
+
+    /// The largest value with the sign of `val`, 0 for 0
+    fn saturated(val: i64) -> Self {
+        match val.signum() {
+            0 => Fixed::zero(),
+            -1 => Fixed(i32::min_value()),
+            _ => Fixed(i32::max_value()),
+        }
+    }
+
+    fn clamped(bits: i64) -> Self {
+        Fixed(bits.max(i64::from(i32::min_value())).min(i64::from(i32::max_value())) as i32)
+    }
+
+    pub fn floor(self) -> i32 {
+        self.0 >> FRAC_BITS
+    }
+
+    pub fn ceil(self) -> i32 {
+        (self.0 + ONE - 1) >> FRAC_BITS
+    }
+
+    /// Halves are rounded up
+    pub fn round(self) -> i32 {
+        (self.0 + ONE / 2) >> FRAC_BITS
+    }
+
+    /// Drops the fraction, rounding towards 0
+    pub fn trunc(self) -> i32 {
+        if self.0 < 0 { -((-self.0) >> FRAC_BITS) } else { self.0 >> FRAC_BITS }
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.abs())
+    }
+
+    pub fn signum(self) -> i32 {
+        self.0.signum()
+    }
+
+    /// Negative values have no root, they result in 0
+    pub fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Fixed::zero();
+        }
+        Fixed(isqrt((self.0 as u64) << FRAC_BITS) as i32)
+    }
+
+    /// Length of the vector (self, other), without overflowing on the way
+    pub fn hypot(self, other: Fixed) -> Self {
+        let x = i64::from(self.0).abs() as u64;
+        let y = i64::from(other.0).abs() as u64;
+        let length = isqrt(x * x + y * y);
+        Fixed(length.min(i32::max_value() as u64) as i32)
+    }
+
+    /// The other short side of a right triangle with this as its longest side,
+    /// 0 if `side` is longer
+    pub fn leg(self, side: Fixed) -> Self {
+        let hypot = i64::from(self.0).abs() as u64;
+        let side = i64::from(side.0).abs() as u64;
+        if side >= hypot {
+            return Fixed::zero();
+        }
+        Fixed(isqrt(hypot * hypot - side * side) as i32)
+    }
+
+    /// Sine of an angle in degrees, interpolated linearly between whole degrees
+    pub fn sin_deg(self) -> Self {
+        let full_turn = Fixed::from(360);
+        let half_turn = Fixed::from(180);
+        let mut degrees = self % full_turn;
+        if degrees < Fixed::zero() {
+            degrees += full_turn;
+        }
+        // The second half of the turn mirrors the first one
+        let (degrees, sign) = if degrees >= half_turn {
+            (degrees - half_turn, -1)
+        } else {
+            (degrees, 1)
+        };
+        let degrees = if degrees > Fixed::from(90) {
+            half_turn - degrees
+        } else {
+            degrees
+        };
+        let whole = degrees.floor() as usize;
+        let frac = i64::from(degrees.0 & (ONE - 1));
+        let low = SIN_TABLE[whole];
+        let high = SIN_TABLE[(whole + 1).min(90)];
+        let sin = low + ((i64::from(high - low) * frac) >> FRAC_BITS) as i32;
+        Fixed(sin * sign)
+    }
+
+    pub fn cos_deg(self) -> Self {
+        (self + Fixed::from(90)).sin_deg()
+    }
+
+    /// Angle of the vector (x, y) in degrees, from -180 to 180.
+    /// Off by a tenth of a degree at most, exact along the axes.
+    pub fn atan2_deg(y: Fixed, x: Fixed) -> Self {
+        if x == Fixed::zero() && y == Fixed::zero() {
+            return Fixed::zero();
+        }
+        let (abs_x, abs_y) = (x.abs(), y.abs());
+        // Mirror everything into the first 45 degrees, where the approximation works
+        let degrees = if abs_y <= abs_x {
+            atan_deg(abs_y / abs_x)
+        } else {
+            Fixed::from(90) - atan_deg(abs_x / abs_y)
+        };
+        let degrees = if x < Fixed::zero() {
+            Fixed::from(180) - degrees
+        } else {
+            degrees
+        };
+        if y < Fixed::zero() { -degrees } else { degrees }
+    }
+}
+
+/// Arctangent in degrees of values from 0 to 1
+fn atan_deg(val: Fixed) -> Fixed {
+    // Polynomial by Rajan et al., its coefficients of 0.2447 and 0.0663 turned into degrees
+    let a = Fixed(918_833);
+    let b = Fixed(248_952);
+    val * 45 - val * (val - Fixed::from(1)) * (a + b * val)
+}
+
+/// Largest integer whose square does not exceed the value
+fn isqrt(val: u64) -> u64 {
+    let mut rem = val;
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    while bit > rem {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// Saturates at the limits instead of wrapping around
+impl From<i32> for Fixed {
+    fn from(val: i32) -> Self {
+        Fixed::checked_from(val).unwrap_or_else(|| if val < 0 {
+            Fixed(i32::min_value())
+        } else {
+            Fixed(i32::max_value())
+        })
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(-self.0)
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((i64::from(self.0) * i64::from(other.0)) >> FRAC_BITS) as i32)
+    }
+}
+
+impl Mul<i32> for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, factor: i32) -> Fixed {
+        Fixed(self.0 * factor)
+    }
+}
+
+/// Saturates at the limits, which is also where dividing by 0 ends up
+impl Div for Fixed {
+    type Output = Fixed;
+
+    fn div(self, other: Fixed) -> Fixed {
+        if other.0 == 0 {
+            return Fixed::saturated(i64::from(self.0));
+        }
+        Fixed::clamped((i64::from(self.0) << FRAC_BITS) / i64::from(other.0))
+    }
+}
+
+/// Saturates at the limits, which is also where dividing by 0 ends up
+impl Div<i32> for Fixed {
+    type Output = Fixed;
+
+    fn div(self, divisor: i32) -> Fixed {
+        if divisor == 0 {
+            return Fixed::saturated(i64::from(self.0));
+        }
+        Fixed::clamped(i64::from(self.0) / i64::from(divisor))
+    }
+}
+
+impl Rem for Fixed {
+    type Output = Fixed;
+
+    fn rem(self, other: Fixed) -> Fixed {
+        Fixed(self.0 % other.0)
+    }
+}
+
+impl FromStr for Fixed {
+    type Err = <f64 as FromStr>::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<f64>().map(Fixed::from_f64)
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_f64())
+    }
+}
+
+impl Serialize for Fixed {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_f64(self.to_f64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Fixed {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        f64::deserialize(deserializer).map(Fixed::from_f64)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_whole_units() {
+        assert_eq!(7.0, Fixed::from(7).to_f64());
+        assert_eq!(-3.0, Fixed::from(-3).to_f64());
+        assert_eq!(Fixed::from(5), Fixed::from_f64(5.0));
+    }
+
+    #[test]
+    fn saturates_out_of_range() {
+        assert_eq!(Some(Fixed::from(32_767)), Fixed::checked_from(32_767));
+        assert_eq!(Some(Fixed::from(-32_768)), Fixed::checked_from(-32_768));
+        assert_eq!(None, Fixed::checked_from(32_768));
+        assert_eq!(None, Fixed::checked_from(-32_769));
+        assert_eq!(Fixed::from_bits(i32::max_value()), Fixed::from(100_000));
+        assert_eq!(Fixed::from_bits(i32::min_value()), Fixed::from(i32::min_value()));
+        assert_eq!(Fixed::from_bits(i32::max_value()), Fixed::from_f64(1e12));
+        assert_eq!(Fixed::from_bits(i32::min_value()), Fixed::from_f64(-1e12));
+    }
+
+    #[test]
+    fn keeps_fractions() {
+        let half = Fixed::from_f64(0.5);
+        assert_eq!(0.5, half.to_f64());
+        assert_eq!(Fixed::from(1), half + half);
+        assert_eq!(0.25, (half * half).to_f64());
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = Fixed::from_f64(7.5);
+        let b = Fixed::from(2);
+        assert_eq!(9.5, (a + b).to_f64());
+        assert_eq!(5.5, (a - b).to_f64());
+        assert_eq!(15.0, (a * b).to_f64());
+        assert_eq!(3.75, (a / b).to_f64());
+        assert_eq!(-7.5, (-a).to_f64());
+        assert_eq!(22.5, (a * 3).to_f64());
+        assert_eq!(2.5, (a / 3).to_f64());
+        assert_eq!(1.5, (a % b).to_f64());
+    }
+
+    #[test]
+    fn division_saturates() {
+        let max = Fixed::from_bits(i32::max_value());
+        let min = Fixed::from_bits(i32::min_value());
+        assert_eq!(max, Fixed::from(3) / Fixed::zero());
+        assert_eq!(min, Fixed::from(-3) / 0);
+        assert_eq!(Fixed::zero(), Fixed::zero() / Fixed::zero());
+        assert_eq!(max, Fixed::from(20_000) / Fixed::from_f64(0.5));
+        assert_eq!(max, min / -1);
+    }
+
+    #[test]
+    fn rounding() {
+        let val = Fixed::from_f64(2.5);
+        assert_eq!(2, val.floor());
+        assert_eq!(3, val.ceil());
+        assert_eq!(3, val.round());
+        let val = Fixed::from_f64(-2.5);
+        assert_eq!(-3, val.floor());
+        assert_eq!(-2, val.ceil());
+        assert_eq!(-2, val.round());
+        assert_eq!(4, Fixed::from(4).ceil());
+        assert_eq!(2, Fixed::from_f64(2.75).trunc());
+        assert_eq!(-2, Fixed::from_f64(-2.75).trunc());
+    }
+
+    #[test]
+    fn ratios() {
+        assert_eq!(Fixed::from_f64(0.25), Fixed::ratio(1, 4));
+        assert_eq!(Fixed::from_f64(-1.5), Fixed::ratio(3, -2));
+        // Rounded down, not towards 0
+        assert_eq!(Fixed::from_bits(21_845), Fixed::ratio(1, 3));
+        assert_eq!(Fixed::from_bits(-21_846), Fixed::ratio(-1, 3));
+        assert_eq!(Fixed::from_bits(i32::max_value()), Fixed::ratio(1 << 20, 1));
+        assert_eq!(Fixed::from_bits(i32::min_value()), Fixed::ratio(-1, 0));
+        assert_eq!(Fixed::zero(), Fixed::ratio(0, 0));
+    }
+
+    #[test]
+    fn ordering() {
+        assert!(Fixed::from_f64(-0.5) < Fixed::zero());
+        assert!(Fixed::from_f64(0.25) < Fixed::from_f64(0.5));
+        assert_eq!(-1, Fixed::from_f64(-0.5).signum());
+        assert_eq!(Fixed::from_f64(0.5), Fixed::from_f64(-0.5).abs());
+    }
+
+    #[test]
+    fn square_roots() {
+        assert_eq!(Fixed::from(3), Fixed::from(9).sqrt());
+        assert_eq!(Fixed::from_f64(1.5), Fixed::from_f64(2.25).sqrt());
+        assert_eq!(Fixed::zero(), Fixed::from(-4).sqrt());
+        assert_eq!(Fixed::from(5), Fixed::from(3).hypot(Fixed::from(-4)));
+        assert_eq!(Fixed::from(30_000), Fixed::from(30_000).hypot(Fixed::zero()));
+        assert_eq!(Fixed::from(4), Fixed::from(5).leg(Fixed::from(-3)));
+        assert_eq!(Fixed::from(30_000), Fixed::from(30_000).leg(Fixed::zero()));
+        assert_eq!(Fixed::zero(), Fixed::from(3).leg(Fixed::from(5)));
+        assert_eq!(46_340, isqrt(2_147_395_600));
+        assert_eq!(46_339, isqrt(2_147_395_599));
+    }
+
+    #[test]
+    fn trigonometry_in_degrees() {
+        assert_eq!(Fixed::zero(), Fixed::zero().sin_deg());
+        assert_eq!(Fixed::from(1), Fixed::from(90).sin_deg());
+        assert_eq!(Fixed::from(-1), Fixed::from(-90).sin_deg());
+        assert_eq!(Fixed::from_f64(0.5), Fixed::from(30).sin_deg());
+        assert_eq!(Fixed::from(1), Fixed::zero().cos_deg());
+        assert_eq!(Fixed::from(-1), Fixed::from(540).cos_deg());
+        for tenth in -3600..3600 {
+            let degrees = f64::from(tenth) / 10.0;
+            let sin = Fixed::from_f64(degrees).sin_deg().to_f64();
+            let cos = Fixed::from_f64(degrees).cos_deg().to_f64();
+            assert!((sin - degrees.to_radians().sin()).abs() < 0.0002);
+            assert!((cos - degrees.to_radians().cos()).abs() < 0.0002);
+        }
+    }
+
+    #[test]
+    fn arctangent_in_degrees() {
+        let atan2 = |y: i32, x: i32| Fixed::atan2_deg(Fixed::from(y), Fixed::from(x));
+        assert_eq!(Fixed::zero(), atan2(0, 5));
+        assert_eq!(Fixed::from(90), atan2(5, 0));
+        assert_eq!(Fixed::from(180), atan2(0, -5));
+        assert_eq!(Fixed::from(-90), atan2(-5, 0));
+        assert_eq!(Fixed::from(45), atan2(3, 3));
+        assert_eq!(Fixed::from(-135), atan2(-3, -3));
+        for &(y, x) in &[(1, 2), (-7, 3), (250, -40), (-1, -300), (13, 14)] {
+            let expected = f64::from(y).atan2(f64::from(x)).to_degrees();
+            assert!((atan2(y, x).to_f64() - expected).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn parses_from_str() {
+        assert_eq!(Ok(Fixed::from_f64(1.25)), "1.25".parse());
+        assert_eq!(Ok(Fixed::from(15)), "15".parse());
+        assert!("fast".parse::<Fixed>().is_err());
+    }
+}
@@ -1,12 +1,266 @@
 extern crate uuid;
 use self::uuid::Uuid;
+use model::comp::Difficulty;
+use model::fixed::Fixed;
+use util::{SeqId, Time};
+use std::fmt;
 
 pub type Id = Uuid;
+/// Handed to every client, so it can resume its session after its connection dropped
+pub type SessionToken = Uuid;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Add, AddAssign, Hash)]
+pub const PLAYERS_PER_MATCH: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RoomCode(pub String);
+impl RoomCode {
+    pub fn generate() -> Self {
+        // Leave out characters that are easily confused with each other
+        const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+        const LEN: usize = 5;
+        let code = Uuid::new_v4().as_bytes()[..LEN]
+            .iter()
+            .map(|byte| ALPHABET[*byte as usize % ALPHABET.len()] as char)
+            .collect();
+        RoomCode(code)
+    }
+}
+impl fmt::Display for RoomCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Add, AddAssign,
+         Hash)]
 pub struct Vector {
-    pub x: i32,
-    pub y: i32,
+    pub x: Fixed,
+    pub y: Fixed,
+}
+impl Vector {
+    /// Vector of whole world units
+    pub fn new(x: i32, y: i32) -> Self {
+        Vector {
+            x: Fixed::from(x),
+            y: Fixed::from(y),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Hash)]
+pub enum Side {
+    Left,
+    Right,
+}
+impl Side {
+    pub fn opposite(&self) -> Side {
+        match *self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+}
+
+/// Which connection controls the paddle on each side
+#[derive(Debug, Clone, Default)]
+pub struct Slots {
+    left: Option<Id>,
+    right: Option<Id>,
+}
+impl Slots {
+    pub fn get(&self, side: Side) -> Option<&Id> {
+        match side {
+            Side::Left => self.left.as_ref(),
+            Side::Right => self.right.as_ref(),
+        }
+    }
+    pub fn free_side(&self) -> Option<Side> {
+        if self.left.is_none() {
+            Some(Side::Left)
+        } else if self.right.is_none() {
+            Some(Side::Right)
+        } else {
+            None
+        }
+    }
+    pub fn is_full(&self) -> bool {
+        self.free_side().is_none()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.left.is_none() && self.right.is_none()
+    }
+    /// Assigns the first free side to the id
+    pub fn claim(&mut self, id: Id) -> Option<Side> {
+        let side = self.free_side()?;
+        match side {
+            Side::Left => self.left = Some(id),
+            Side::Right => self.right = Some(id),
+        }
+        Some(side)
+    }
+    pub fn release(&mut self, id: &Id) -> Option<Side> {
+        if self.left.as_ref() == Some(id) {
+            self.left = None;
+            Some(Side::Left)
+        } else if self.right.as_ref() == Some(id) {
+            self.right = None;
+            Some(Side::Right)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Score {
+    pub left: u32,
+    pub right: u32,
+}
+impl Score {
+    pub fn get(&self, side: Side) -> u32 {
+        match side {
+            Side::Left => self.left,
+            Side::Right => self.right,
+        }
+    }
+    pub fn credit(&mut self, side: Side) {
+        match side {
+            Side::Left => self.left += 1,
+            Side::Right => self.right += 1,
+        }
+    }
+}
+
+/// Emitted whenever the ball leaves the field behind a paddle
+#[derive(Debug, Clone, Serialize)]
+pub struct Goal {
+    pub scorer: Side,
+}
+
+pub struct BotConfig {
+    /// Ticks a lone player waits before getting a bot as opponent
+    pub fill_timeout_ticks: u32,
+    pub difficulty: Difficulty,
+}
+
+pub struct LagCompensation {
+    /// Hits are never checked against states older than this
+    pub max_rewind_ticks: usize,
+}
+
+/// Fixed length of every tick, so the game runs at the same speed at any tick rate.
+/// Speeds are configured in units per second, this turns them into units per tick.
+#[derive(Debug, Clone, Copy)]
+pub struct DeltaTime {
+    pub ticks_per_sec: u32,
+}
+
+impl DeltaTime {
+    /// How far something going at `per_sec` gets in a single tick
+    pub fn scale(&self, per_sec: Fixed) -> Fixed {
+        per_sec / self.ticks_per_sec as i32
+    }
+
+    pub fn scale_vector(&self, per_sec: &Vector) -> Vector {
+        Vector {
+            x: self.scale(per_sec.x),
+            y: self.scale(per_sec.y),
+        }
+    }
+
+    /// How many ticks it takes for the given milliseconds to pass, rounded up
+    pub fn ticks_in(&self, ms: Time) -> SeqId {
+        let ms_ticks = ms.saturating_mul(u64::from(self.ticks_per_sec));
+        (ms_ticks.saturating_add(999) / 1000).min(u64::from(SeqId::max_value())) as SeqId
+    }
+}
+
+/// Hash over the positions and velocities of all actors after a tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum {
+    pub tick: SeqId,
+    pub value: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Phase {
+    WaitingForPlayers,
+    Countdown,
+    Playing,
+    Paused,
+    Finished,
+}
+
+pub struct MatchState {
+    pub score_limit: u32,
+    pub countdown_ticks: u32,
+    pub game_over_ticks: u32,
+    phase: Phase,
+    winner: Option<Side>,
+    ticks_in_phase: u32,
+    changed: bool,
+}
+impl MatchState {
+    pub fn new(score_limit: u32, countdown_ticks: u32, game_over_ticks: u32) -> Self {
+        MatchState {
+            score_limit,
+            countdown_ticks,
+            game_over_ticks,
+            phase: Phase::WaitingForPlayers,
+            winner: None,
+            ticks_in_phase: 0,
+            changed: true,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+    pub fn winner(&self) -> Option<Side> {
+        self.winner
+    }
+    pub fn ticks_in_phase(&self) -> u32 {
+        self.ticks_in_phase
+    }
+    pub fn ticks_left(&self) -> u32 {
+        let duration = match self.phase {
+            Phase::Countdown => self.countdown_ticks,
+            Phase::Finished => self.game_over_ticks,
+            _ => return 0,
+        };
+        duration.saturating_sub(self.ticks_in_phase)
+    }
+
+    /// Everything moves, including the ball
+    pub fn is_playing(&self) -> bool {
+        self.phase == Phase::Playing
+    }
+    /// Paddles may move, but the ball might not
+    pub fn moves_paddles(&self) -> bool {
+        self.phase == Phase::Playing || self.phase == Phase::Countdown
+    }
+
+    pub fn set_phase(&mut self, phase: Phase) {
+        if self.phase != phase {
+            self.phase = phase;
+            self.winner = None;
+            self.ticks_in_phase = 0;
+            self.changed = true;
+        }
+    }
+    pub fn finish(&mut self, winner: Side) {
+        self.set_phase(Phase::Finished);
+        self.winner = Some(winner);
+    }
+    pub fn tick(&mut self) {
+        self.ticks_in_phase += 1;
+    }
+    /// Returns whether the phase changed since the last call
+    pub fn take_changed(&mut self) -> bool {
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
 }
 
 #[macro_export]
@@ -18,6 +272,7 @@ macro_rules! vectype {
             PartialEq,
             Eq,
             Serialize,
+            Deserialize,
             Add,
             AddAssign,
             Hash,
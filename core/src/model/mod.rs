@@ -1,4 +1,6 @@
+pub mod fixed;
 #[macro_use]
 pub mod game;
 pub mod network;
+pub mod message_pack;
 pub mod comp;
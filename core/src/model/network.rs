@@ -1,47 +1,352 @@
 extern crate serde;
-extern crate serde_json;
 
-use self::serde::ser::Serialize;
-use self::serde_json::Value;
-use model::game::Id;
-use model::comp::Actor;
-use std::fmt::Debug;
+use self::serde::{Serialize, Serializer};
+use self::serde::ser::SerializeStruct;
+use model::game::{Id, Score, Goal, MatchState, Phase, RoomCode, Side, SessionToken};
+use model::comp::{Actor, Pos, Vel};
+use util::{SeqId, Time};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 
-#[derive(Debug, Clone, Serialize)]
-pub enum OpCode {
-    Greeting,
-    Spawn,
-    Despawn,
-    WorldUpdate,
+/// How many ticks a client may fall behind with its acknowledgements
+/// before we stop using deltas and send it full snapshots again
+pub const MAX_SNAPSHOT_AGE: usize = 32;
+
+/// Clients speaking another version get disconnected right after saying hello
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Longer chat messages get cut off
+pub const MAX_CHAT_LEN: usize = 200;
+
+/// How often we measure the round trip time to every client
+pub const PING_INTERVAL_MS: Time = 1000;
+
+/// Inputs meant for a tick further in the past than this are rejected
+pub const MAX_INPUT_DELAY_TICKS: SeqId = 8;
+
+/// Inputs meant for a tick further in the future than this are rejected
+pub const MAX_INPUT_LEAD_TICKS: SeqId = 30;
+
+/// Inputs waiting to be applied per client, anything beyond is dropped
+pub const MAX_BUFFERED_INPUTS: usize = 64;
+
+/// Name of the newtype `WireId` writes, so that `message_pack` can tell ids from other strings
+pub const WIRE_ID: &str = "WireId";
+
+/// Serializes an id as a string, which `message_pack::to_vec` turns into 16 raw bytes
+pub struct WireId<'a>(pub &'a Id);
+
+impl<'a> Serialize for WireId<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_newtype_struct(WIRE_ID, &self.0.hyphenated().to_string())
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct ServerMsg<T>
+pub fn serialize_id<S>(id: &Id, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    WireId(id).serialize(serializer)
+}
+
+fn serialize_ids<S>(ids: &[&Id], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(ids.iter().map(|id| WireId(id)))
+}
+
+fn serialize_actor_states<S>(
+    actors: &HashMap<&Id, &ActorState>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
 where
-    T: Serialize + Debug,
+    S: Serializer,
 {
-    pub opcode: OpCode,
-    pub payload: T,
+    serializer.collect_map(actors.iter().map(|(id, state)| (WireId(id), state)))
 }
-impl ServerMsg<Vec<Value>> {
-    pub fn new_greeting(own_id: &Id, actors: &[&Actor]) -> Self {
-        ServerMsg {
-            opcode: OpCode::Greeting,
-            payload: vec![json!(own_id), json!(actors)],
+
+/// Everything we send to clients, stamped with the tick it was sent in.
+/// Serialized as {"opcode": ..., "payload": ..., "tick": ...},
+/// or as [opcode, payload, tick] in MessagePack, where every struct is an array.
+#[derive(Debug)]
+pub struct ServerMsg<'a> {
+    pub tick: SeqId,
+    pub message: ServerMessage<'a>,
+}
+
+impl<'a> ServerMsg<'a> {
+    pub fn new(tick: SeqId, message: ServerMessage<'a>) -> Self {
+        ServerMsg { tick, message }
+    }
+}
+
+impl<'a> Serialize for ServerMsg<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut msg = serializer.serialize_struct("ServerMsg", 3)?;
+        msg.serialize_field("opcode", self.message.opcode())?;
+        match self.message {
+            ServerMessage::Greeting(ref greeting) => msg.serialize_field("payload", greeting)?,
+            ServerMessage::Spawn(actor) => msg.serialize_field("payload", actor)?,
+            ServerMessage::Despawn(id) => msg.serialize_field("payload", &WireId(id))?,
+            ServerMessage::WorldUpdate(ref update) => msg.serialize_field("payload", update)?,
+            ServerMessage::Score(ref update) => msg.serialize_field("payload", update)?,
+            ServerMessage::MatchState(ref update) => msg.serialize_field("payload", update)?,
+            ServerMessage::Pong(ref pong) => msg.serialize_field("payload", pong)?,
+            ServerMessage::Ping(ref ping) => msg.serialize_field("payload", ping)?,
+            ServerMessage::Chat(chat) => msg.serialize_field("payload", chat)?,
         }
+        msg.serialize_field("tick", &self.tick)?;
+        msg.end()
     }
 }
-impl ServerMsg<Value> {
-    pub fn new_spawn(new_actor: &Actor) -> Self {
-        ServerMsg {
-            opcode: OpCode::Spawn,
-            payload: json!(new_actor),
+
+#[derive(Debug)]
+pub enum ServerMessage<'a> {
+    Greeting(Greeting<'a>),
+    Spawn(&'a Actor),
+    Despawn(&'a Id),
+    WorldUpdate(WorldUpdate<'a>),
+    Score(ScoreUpdate<'a>),
+    MatchState(MatchStateUpdate<'a>),
+    Pong(Pong),
+    /// Has to be answered with a Pong carrying the same id
+    Ping(Ping),
+    Chat(&'a Chat),
+}
+
+impl<'a> ServerMessage<'a> {
+    pub fn opcode(&self) -> &'static str {
+        match *self {
+            ServerMessage::Greeting(_) => "Greeting",
+            ServerMessage::Spawn(_) => "Spawn",
+            ServerMessage::Despawn(_) => "Despawn",
+            ServerMessage::WorldUpdate(_) => "WorldUpdate",
+            ServerMessage::Score(_) => "Score",
+            ServerMessage::MatchState(_) => "MatchState",
+            ServerMessage::Pong(_) => "Pong",
+            ServerMessage::Ping(_) => "Ping",
+            ServerMessage::Chat(_) => "Chat",
         }
     }
-    pub fn new_despawn(id: &Id) -> Self {
-        ServerMsg {
-            opcode: OpCode::Despawn,
-            payload: json!(id),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Greeting<'a> {
+    pub version: u32,
+    #[serde(serialize_with = "serialize_id")]
+    pub id: &'a Id,
+    pub actors: &'a [&'a Actor],
+    pub score: &'a Score,
+    pub room: &'a RoomCode,
+    /// Send it with the next hello to come back as the same player
+    #[serde(serialize_with = "serialize_id")]
+    pub session: &'a SessionToken,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScoreUpdate<'a> {
+    pub scorer: Side,
+    pub score: &'a Score,
+}
+
+impl<'a> ScoreUpdate<'a> {
+    pub fn new(goal: &Goal, score: &'a Score) -> Self {
+        ScoreUpdate {
+            scorer: goal.scorer,
+            score,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchStateUpdate<'a> {
+    pub phase: Phase,
+    pub winner: Option<Side>,
+    pub ticks_left: u32,
+    pub score: &'a Score,
+}
+
+impl<'a> MatchStateUpdate<'a> {
+    pub fn new(match_state: &MatchState, score: &'a Score) -> Self {
+        MatchStateUpdate {
+            phase: match_state.phase(),
+            winner: match_state.winner(),
+            ticks_left: match_state.ticks_left(),
+            score,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Pong {
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Ping {
+    pub id: u32,
+}
+
+/// Smoothed round trip time and its variation, estimated like TCP does
+#[derive(Debug, Clone, Default)]
+pub struct Latency {
+    rtt: Option<f64>,
+    jitter: f64,
+    last_ping: Option<Time>,
+    /// Cleared once its pong arrives, so a repeated pong doesn't count twice
+    pending: Option<(u32, Time)>,
+    next_ping_id: u32,
+}
+
+impl Latency {
+    /// Returns the id of a new ping if the last one is long enough ago.
+    /// Pongs to older pings are ignored afterwards.
+    pub fn start_ping(&mut self, now: Time) -> Option<u32> {
+        if let Some(sent) = self.last_ping {
+            if now < sent + PING_INTERVAL_MS {
+                return None;
+            }
+        }
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.last_ping = Some(now);
+        self.pending = Some((id, now));
+        Some(id)
+    }
+
+    pub fn finish_ping(&mut self, id: u32, now: Time) {
+        let sent = match self.pending {
+            Some((pending_id, sent)) if pending_id == id => sent,
+            _ => return,
+        };
+        self.pending = None;
+        let sample = now.saturating_sub(sent) as f64;
+        match self.rtt {
+            Some(rtt) => {
+                self.jitter = 0.75 * self.jitter + 0.25 * (sample - rtt).abs();
+                self.rtt = Some(0.875 * rtt + 0.125 * sample);
+            }
+            None => {
+                self.jitter = sample / 2.0;
+                self.rtt = Some(sample);
+            }
+        }
+    }
+
+    /// None until the first pong arrived
+    pub fn rtt(&self) -> Option<Time> {
+        self.rtt.map(|rtt| rtt.round() as Time)
+    }
+
+    pub fn jitter(&self) -> Time {
+        self.jitter.round() as Time
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Chat {
+    #[serde(serialize_with = "serialize_id")]
+    pub from: Id,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActorState {
+    pub pos: Pos,
+    pub vel: Vel,
+}
+
+pub type Snapshot = HashMap<Id, ActorState>;
+
+/// The last few snapshots we sent, used as baselines for deltas
+#[derive(Debug, Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<(SeqId, Snapshot)>,
+}
+
+impl SnapshotHistory {
+    pub fn push(&mut self, tick: SeqId, snapshot: Snapshot) {
+        if self.snapshots.len() >= MAX_SNAPSHOT_AGE {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((tick, snapshot));
+    }
+
+    pub fn get(&self, tick: SeqId) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|&&(t, _)| t == tick).map(
+            |&(_, ref snapshot)| snapshot,
+        )
+    }
+
+    pub fn latest(&self) -> Option<&Snapshot> {
+        self.snapshots.back().map(|&(_, ref snapshot)| snapshot)
+    }
+
+    /// The snapshot a client acknowledged, if we still have it.
+    /// Clients that fell too far behind get everything again.
+    pub fn baseline(&self, acked_tick: Option<SeqId>) -> Option<(SeqId, &Snapshot)> {
+        acked_tick.and_then(|tick| self.get(tick).map(|baseline| (tick, baseline)))
+    }
+}
+
+/// A snapshot containing only what changed since the given baseline.
+/// Without a baseline, it contains every actor.
+#[derive(Debug, Serialize)]
+pub struct WorldUpdate<'a> {
+    /// Milliseconds since the unix epoch, lets clients sync their clocks
+    pub server_time: Time,
+    pub last_input: u32,
+    pub baseline: Option<SeqId>,
+    #[serde(serialize_with = "serialize_actor_states")]
+    pub actors: HashMap<&'a Id, &'a ActorState>,
+    #[serde(serialize_with = "serialize_ids")]
+    pub removed: Vec<&'a Id>,
+    /// Only sent in deterministic mode, see `Checksum`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u32>,
+}
+
+impl<'a> WorldUpdate<'a> {
+    pub fn new(
+        server_time: Time,
+        last_input: u32,
+        snapshot: &'a Snapshot,
+        baseline: Option<(SeqId, &'a Snapshot)>,
+    ) -> Self {
+        match baseline {
+            Some((tick, baseline)) => {
+                WorldUpdate {
+                    server_time,
+                    last_input,
+                    baseline: Some(tick),
+                    actors: snapshot
+                        .iter()
+                        .filter(|&(id, state)| baseline.get(id) != Some(state))
+                        .collect(),
+                    removed: baseline
+                        .keys()
+                        .filter(|id| !snapshot.contains_key(*id))
+                        .collect(),
+                    checksum: None,
+                }
+            }
+            None => {
+                WorldUpdate {
+                    server_time,
+                    last_input,
+                    baseline: None,
+                    actors: snapshot.iter().collect(),
+                    removed: Vec::new(),
+                    checksum: None,
+                }
+            }
         }
     }
 }
@@ -53,8 +358,348 @@ pub enum Command {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ClientMsg {
+pub struct InputMsg {
     pub id: u32,
+    /// The tick the client wants this to be applied in
+    pub tick: SeqId,
+    /// The tick of the state the client was looking at when it made this
+    pub seen_tick: SeqId,
     pub command: Command,
     pub active: bool,
 }
+
+/// Inputs of a single client waiting for the tick they are meant for
+#[derive(Debug, Default)]
+pub struct JitterBuffer {
+    inputs: Vec<InputMsg>,
+}
+
+impl JitterBuffer {
+    /// Returns false if the buffer is full and the input was dropped
+    pub fn push(&mut self, input: InputMsg) -> bool {
+        if self.inputs.len() >= MAX_BUFFERED_INPUTS {
+            return false;
+        }
+        self.inputs.push(input);
+        true
+    }
+
+    /// Throws away inputs too far in the past or future and returns how many there were.
+    /// Slightly late inputs are still applied, just a bit later than intended.
+    pub fn reject_invalid(&mut self, curr_tick: SeqId) -> usize {
+        let len = self.inputs.len();
+        self.inputs.retain(|input| {
+            input.tick.saturating_add(MAX_INPUT_DELAY_TICKS) >= curr_tick &&
+                input.tick <= curr_tick.saturating_add(MAX_INPUT_LEAD_TICKS)
+        });
+        len - self.inputs.len()
+    }
+
+    /// Removes all inputs due by the given tick, in the order they are meant to happen
+    pub fn take_due(&mut self, curr_tick: SeqId) -> Vec<InputMsg> {
+        let (mut due, pending): (Vec<InputMsg>, Vec<InputMsg>) = self.inputs
+            .drain(..)
+            .partition(|input| input.tick <= curr_tick);
+        self.inputs = pending;
+        due.sort_by_key(|input| (input.tick, input.id));
+        due
+    }
+}
+
+/// Everything clients may send us.
+/// Serialized as {"Variant": payload}, e.g. {"Ack": 42}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum ClientMessage {
+    /// Has to be the first message, nothing else is accepted before it.
+    /// Presenting the session of a dropped connection resumes it.
+    Hello {
+        version: u32,
+        format: Format,
+        session: Option<SessionToken>,
+    },
+    Input(InputMsg),
+    /// Answered with a Pong carrying the same id
+    Ping { id: u32 },
+    /// Answers our Ping with the same id
+    Pong { id: u32 },
+    Join(RoomRequest),
+    /// Leave the current room without disconnecting
+    Leave,
+    Chat(String),
+    /// The client has received the snapshot of the given tick
+    Ack(SeqId),
+}
+
+/// Ways a client can break the rules, each one costs it a strike
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    /// Sent more messages than the rate limit allows
+    Flooding,
+    /// Sent something that isn't a message we know
+    Malformed,
+    /// Reused an input id or went backwards
+    StaleInput,
+    /// Acknowledged a tick we haven't sent yet
+    FutureAck,
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            Offense::Flooding => "flooding",
+            Offense::Malformed => "malformed message",
+            Offense::StaleInput => "stale input id",
+            Offense::FutureAck => "acknowledging the future",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Encoding of the messages we send to a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    Json,
+    MessagePack,
+}
+impl Default for Format {
+    fn default() -> Self {
+        Format::Json
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum RoomRequest {
+    /// Open a new room and move there
+    Create,
+    /// Move to the room with the given code
+    Existing(RoomCode),
+    /// Move to any room that still has a free slot
+    Auto,
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate serde_json;
+
+    use super::*;
+    use model::message_pack;
+    use model::game::Vector;
+
+    #[test]
+    fn json_writes_ids_as_strings() {
+        let id = Id::new_v4();
+        let msg = ServerMsg::new(3, ServerMessage::Despawn(&id));
+        let expected = format!(r#"{{"opcode":"Despawn","payload":"{}","tick":3}}"#, id);
+        assert_eq!(expected, serde_json::to_string(&msg).unwrap());
+    }
+
+    #[test]
+    fn message_pack_writes_ids_as_bytes() {
+        let id = Id::new_v4();
+        let msg = ServerMsg::new(3, ServerMessage::Despawn(&id));
+        let encoded = message_pack::to_vec(&msg).unwrap();
+        // [opcode, payload, tick]
+        let mut expected = vec![0x93, 0xa7];
+        expected.extend(b"Despawn");
+        expected.extend(&[0xc4, 16]);
+        expected.extend(id.as_bytes());
+        expected.push(3);
+        assert_eq!(expected, encoded);
+    }
+
+    #[test]
+    fn message_pack_writes_nested_ids_as_bytes() {
+        let chat = Chat {
+            from: Id::new_v4(),
+            text: "hi".to_string(),
+        };
+        let msg = ServerMsg::new(5, ServerMessage::Chat(&chat));
+        let encoded = message_pack::to_vec(&msg).unwrap();
+        let mut expected = vec![0x93, 0xa4];
+        expected.extend(b"Chat");
+        expected.extend(&[0x92, 0xc4, 16]);
+        expected.extend(chat.from.as_bytes());
+        expected.push(0xa2);
+        expected.extend(b"hi");
+        expected.push(5);
+        assert_eq!(expected, encoded);
+        // Nothing carries over to the next message
+        let json = serde_json::to_value(&msg).unwrap();
+        let from = chat.from.hyphenated().to_string();
+        assert_eq!(Some(from.as_str()), json["payload"]["from"].as_str());
+    }
+
+    #[test]
+    fn message_pack_writes_structs_as_arrays() {
+        let msg = ServerMsg::new(300, ServerMessage::Ping(Ping { id: 7 }));
+        let encoded = message_pack::to_vec(&msg).unwrap();
+        let mut expected = vec![0x93, 0xa4];
+        expected.extend(b"Ping");
+        expected.extend(&[0x91, 7, 0xcd, 0x01, 0x2c]);
+        assert_eq!(expected, encoded);
+    }
+
+    fn state(x: i32) -> ActorState {
+        ActorState {
+            pos: Pos::from(Vector::new(x, 0)),
+            vel: Vel::from(Vector::new(0, 0)),
+        }
+    }
+
+    #[test]
+    fn world_update_without_baseline_has_everything() {
+        let (a, b) = (Id::new_v4(), Id::new_v4());
+        let snapshot: Snapshot = vec![(a, state(1)), (b, state(2))].into_iter().collect();
+        let update = WorldUpdate::new(0, 0, &snapshot, None);
+        assert_eq!(None, update.baseline);
+        assert_eq!(2, update.actors.len());
+        assert!(update.removed.is_empty());
+    }
+
+    #[test]
+    fn world_update_only_has_changes() {
+        let (moved, still, gone, new) = (Id::new_v4(), Id::new_v4(), Id::new_v4(), Id::new_v4());
+        let baseline: Snapshot = vec![(moved, state(1)), (still, state(2)), (gone, state(3))]
+            .into_iter()
+            .collect();
+        let snapshot: Snapshot = vec![(moved, state(5)), (still, state(2)), (new, state(4))]
+            .into_iter()
+            .collect();
+        let update = WorldUpdate::new(0, 0, &snapshot, Some((7, &baseline)));
+        assert_eq!(Some(7), update.baseline);
+        assert_eq!(2, update.actors.len());
+        assert_eq!(Some(&&state(5)), update.actors.get(&moved));
+        assert_eq!(Some(&&state(4)), update.actors.get(&new));
+        assert_eq!(vec![&gone], update.removed);
+    }
+
+    #[test]
+    fn snapshot_history_forgets_old_baselines() {
+        let mut history = SnapshotHistory::default();
+        for tick in 0..MAX_SNAPSHOT_AGE as SeqId + 1 {
+            let snapshot: Snapshot = vec![(Id::nil(), state(tick as i32))].into_iter().collect();
+            history.push(tick, snapshot);
+        }
+        assert!(history.get(0).is_none());
+        assert!(history.get(1).is_some());
+        let latest = MAX_SNAPSHOT_AGE as SeqId;
+        assert_eq!(Some(&state(latest as i32)), history.latest().unwrap().get(&Id::nil()));
+    }
+
+    #[test]
+    fn unknown_acks_fall_back_to_full_snapshots() {
+        let mut history = SnapshotHistory::default();
+        history.push(3, Snapshot::new());
+        assert_eq!(Some(3), history.baseline(Some(3)).map(|(tick, _)| tick));
+        assert!(history.baseline(Some(2)).is_none());
+        assert!(history.baseline(None).is_none());
+    }
+
+    #[test]
+    fn latency_smooths_round_trips() {
+        let mut latency = Latency::default();
+        assert_eq!(None, latency.rtt());
+        let id = latency.start_ping(0).unwrap();
+        latency.finish_ping(id, 100);
+        assert_eq!(Some(100), latency.rtt());
+        assert_eq!(50, latency.jitter());
+        // Too soon for the next one
+        assert_eq!(None, latency.start_ping(999));
+        let id = latency.start_ping(1000).unwrap();
+        latency.finish_ping(id, 1300);
+        // 7/8 of the old estimate and 1/8 of the new sample
+        assert_eq!(Some(125), latency.rtt());
+        // 3/4 of the old jitter and 1/4 of the deviation from the estimate
+        assert_eq!(88, latency.jitter());
+    }
+
+    #[test]
+    fn latency_ignores_unexpected_pongs() {
+        let mut latency = Latency::default();
+        latency.finish_ping(0, 100);
+        assert_eq!(None, latency.rtt());
+        let stale = latency.start_ping(0).unwrap();
+        let id = latency.start_ping(1000).unwrap();
+        assert!(stale != id);
+        latency.finish_ping(stale, 1050);
+        latency.finish_ping(id + 1, 1050);
+        assert_eq!(None, latency.rtt());
+        latency.finish_ping(id, 1100);
+        assert_eq!(Some(100), latency.rtt());
+        // Answered already
+        latency.finish_ping(id, 1900);
+        assert_eq!(Some(100), latency.rtt());
+    }
+
+    fn input(id: u32, tick: SeqId, active: bool) -> InputMsg {
+        InputMsg {
+            id,
+            tick,
+            seen_tick: tick,
+            command: Command::MoveUp,
+            active,
+        }
+    }
+
+    #[test]
+    fn jitter_buffer_rejects_late_and_early_inputs() {
+        let curr_tick = 100;
+        let earliest = curr_tick - MAX_INPUT_DELAY_TICKS;
+        let latest = curr_tick + MAX_INPUT_LEAD_TICKS;
+        let mut buffer = JitterBuffer::default();
+        for (id, &tick) in [earliest - 1, earliest, latest, latest + 1].iter().enumerate() {
+            buffer.push(input(id as u32, tick, true));
+        }
+        assert_eq!(2, buffer.reject_invalid(curr_tick));
+        let kept: Vec<SeqId> = buffer.take_due(latest).iter().map(|input| input.tick).collect();
+        assert_eq!(vec![earliest, latest], kept);
+    }
+
+    #[test]
+    fn jitter_buffer_rejects_inputs_from_the_end_of_time() {
+        let mut buffer = JitterBuffer::default();
+        buffer.push(input(1, u32::max_value(), true));
+        assert_eq!(1, buffer.reject_invalid(100));
+        buffer.push(input(2, u32::max_value(), true));
+        assert_eq!(0, buffer.reject_invalid(u32::max_value()));
+    }
+
+    #[test]
+    fn jitter_buffer_is_bounded() {
+        let mut buffer = JitterBuffer::default();
+        for id in 0..MAX_BUFFERED_INPUTS {
+            assert!(buffer.push(input(id as u32, 10, true)));
+        }
+        assert!(!buffer.push(input(MAX_BUFFERED_INPUTS as u32, 10, true)));
+        assert_eq!(MAX_BUFFERED_INPUTS, buffer.take_due(10).len());
+    }
+
+    #[test]
+    fn jitter_buffer_holds_inputs_until_due() {
+        let mut buffer = JitterBuffer::default();
+        buffer.push(input(3, 12, true));
+        buffer.push(input(2, 11, false));
+        buffer.push(input(1, 10, true));
+        let due: Vec<u32> = buffer.take_due(11).iter().map(|input| input.id).collect();
+        assert_eq!(vec![1, 2], due);
+        assert!(buffer.take_due(11).is_empty());
+        let due: Vec<u32> = buffer.take_due(12).iter().map(|input| input.id).collect();
+        assert_eq!(vec![3], due);
+    }
+
+    #[test]
+    fn jitter_buffer_keeps_inputs_for_the_same_tick() {
+        let mut buffer = JitterBuffer::default();
+        // Pressed and released within a single tick, arriving out of order
+        buffer.push(input(2, 5, false));
+        buffer.push(input(1, 5, true));
+        assert_eq!(0, buffer.reject_invalid(5));
+        let due: Vec<(u32, bool)> = buffer
+            .take_due(5)
+            .iter()
+            .map(|input| (input.id, input.active))
+            .collect();
+        assert_eq!(vec![(1, true), (2, false)], due);
+    }
+}
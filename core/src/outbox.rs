@@ -0,0 +1,98 @@
+extern crate futures;
+extern crate futures_cpupool;
+extern crate websocket_server;
+
+use self::futures::{Future, Sink, Stream};
+use self::futures::sync::mpsc::{self, Sender};
+use self::futures_cpupool::CpuPool;
+use self::websocket_server::{SendChannel, Message};
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Number of messages sitting in outboxes, as seen by the last tick
+#[derive(Debug, Clone, Default)]
+pub struct OutboxStats {
+    /// Summed over all clients
+    pub queued: usize,
+    /// Of the client furthest behind
+    pub deepest: usize,
+}
+
+/// Bounded queue of messages for a single client.
+/// The messages are delivered on a thread pool, so a slow client never holds up the tick.
+pub struct Outbox {
+    queue: Mutex<Sender<Message>>,
+    send_channel: SendChannel,
+    pool: CpuPool,
+    depth: Arc<AtomicUsize>,
+    capacity: usize,
+    has_overflowed: AtomicBool,
+}
+
+impl Outbox {
+    pub fn new(send_channel: SendChannel, capacity: usize, pool: &CpuPool) -> Self {
+        let (queue, receiver) = mpsc::channel(capacity);
+        let depth = Arc::new(AtomicUsize::new(0));
+        let delivered = depth.clone();
+        // Only count a message as delivered once the connection took it
+        let delivery = receiver
+            .fold(send_channel.clone(), move |sink, msg| {
+                let delivered = delivered.clone();
+                sink.send(msg)
+                    .map(move |sink| {
+                        delivered.fetch_sub(1, Ordering::SeqCst);
+                        sink
+                    })
+                    .map_err(|_| ())
+            })
+            .map(|_| ());
+        pool.spawn(delivery).forget();
+        Outbox {
+            queue: Mutex::new(queue),
+            send_channel,
+            pool: pool.clone(),
+            depth,
+            capacity,
+            has_overflowed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues the message without waiting for the client.
+    /// Once the outbox has been full, it stays marked as overflowed.
+    pub fn push(&self, msg: Message) {
+        if self.has_overflowed() {
+            return;
+        }
+        // The channel reserves an extra slot per sender, so we enforce the capacity ourselves
+        if self.depth() >= self.capacity {
+            self.has_overflowed.store(true, Ordering::SeqCst);
+            return;
+        }
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        if let Err(err) = self.queue.lock().unwrap().try_send(msg) {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            // A closed connection gets cleaned up on disconnect
+            if err.is_full() {
+                self.has_overflowed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    pub fn has_overflowed(&self) -> bool {
+        self.has_overflowed.load(Ordering::SeqCst)
+    }
+
+    /// Asks the client to close the connection, skipping everything still queued
+    pub fn close(&self) {
+        let close = self.send_channel
+            .clone()
+            .send(Message::Close(None))
+            .then(|_| Ok::<(), ()>(()));
+        self.pool.spawn(close).forget();
+    }
+}
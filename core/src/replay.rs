@@ -0,0 +1,415 @@
+extern crate specs;
+extern crate serde_json;
+
+use self::specs::{DispatcherBuilder, World, Entity, Join};
+
+use model::comp::{Actor, ActorKind, Bot, Difficulty, Pos, Vel, ToSpawn, ToDespawn, Suspended};
+use model::game::{Id, RoomCode, MatchState, LagCompensation, DeltaTime, Checksum};
+use model::network::{ActorState, InputMsg, JitterBuffer, Snapshot};
+use room::RoomConfig;
+use config::GameConfig;
+use system::{Lifecycle, InputHandler, Spawn, Physics, Bounce, Despawn, InputMap, hash_actors};
+use collision::{World as CollisionWorld, History};
+use util::SeqId;
+use bootstrap;
+
+use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Bump whenever the file format changes
+pub const REPLAY_VERSION: u32 = 1;
+
+pub type SharedRecorder = Arc<RwLock<Recorder>>;
+
+/// Everything needed to set up a room the way it was when recording started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    pub room: RoomCode,
+    pub updates_per_sec: u64,
+    pub score_limit: u32,
+    pub countdown_ticks: u32,
+    pub game_over_ticks: u32,
+    /// How far hits were checked against past paddle positions
+    pub max_rewind_ticks: usize,
+    pub game: GameConfig,
+}
+
+impl Header {
+    pub fn new(room: &RoomCode, config: &RoomConfig) -> Self {
+        Header {
+            version: REPLAY_VERSION,
+            room: room.clone(),
+            updates_per_sec: config.updates_per_sec,
+            score_limit: config.score_limit,
+            countdown_ticks: config.countdown_ticks(),
+            game_over_ticks: config.game_over_ticks(),
+            max_rewind_ticks: config.max_rewind_ticks(),
+            game: config.game.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpawnRecord {
+    pub id: Id,
+    pub kind: ActorKind,
+    /// Only set for bots
+    pub difficulty: Option<Difficulty>,
+}
+
+/// What happened right before and during a single tick
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TickRecord {
+    pub tick: SeqId,
+    /// Despawned since the previous tick
+    pub removed: Vec<Id>,
+    pub spawned: Vec<SpawnRecord>,
+    /// Marked for despawning, they are still around during this tick
+    pub marked: Vec<Id>,
+    /// Players whose connection dropped
+    pub suspended: Vec<Id>,
+    /// Players that came back on a new connection
+    pub resumed: Vec<Id>,
+    pub inputs: Vec<(Id, InputMsg)>,
+    /// Only known in deterministic mode
+    pub checksum: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndRecord {
+    pub tick: SeqId,
+    pub actors: Snapshot,
+}
+
+/// A replay file holds one JSON encoded entry per line, starting with the header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Entry {
+    Header(Header),
+    Tick(TickRecord),
+    End(EndRecord),
+}
+
+/// Writes what happens in a room to a replay file, does nothing while disabled
+#[derive(Default)]
+pub struct Recorder {
+    out: Option<BufWriter<File>>,
+    known: HashSet<Id>,
+    marked: HashSet<Id>,
+    suspended: HashSet<Id>,
+    removed: Vec<Id>,
+    curr: Option<TickRecord>,
+}
+
+impl Recorder {
+    pub fn disabled() -> Self {
+        Recorder::default()
+    }
+
+    pub fn create(path: &Path, header: Header) -> io::Result<Self> {
+        let mut recorder = Recorder {
+            out: Some(BufWriter::new(File::create(path)?)),
+            ..Recorder::default()
+        };
+        recorder.write(&Entry::Header(header));
+        Ok(recorder)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.out.is_some()
+    }
+
+    /// Finishes the previous tick, `checksum` belongs to it if the ticks match
+    pub fn begin_tick(&mut self, tick: SeqId, checksum: Option<Checksum>) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.flush_tick(checksum);
+        self.curr = Some(TickRecord {
+            tick,
+            removed: self.removed.drain(..).collect(),
+            ..TickRecord::default()
+        });
+    }
+
+    /// Records actors the first time we see them, once they get marked for despawning
+    /// and whenever they get suspended or resumed
+    pub fn observe(
+        &mut self,
+        actor: &Actor,
+        difficulty: Option<&Difficulty>,
+        is_marked: bool,
+        is_suspended: bool,
+    ) {
+        let curr = match self.curr {
+            Some(ref mut curr) => curr,
+            None => return,
+        };
+        if self.known.insert(actor.id) {
+            curr.spawned.push(SpawnRecord {
+                id: actor.id,
+                kind: actor.kind.clone(),
+                difficulty: difficulty.cloned(),
+            });
+        }
+        if is_marked && self.marked.insert(actor.id) {
+            curr.marked.push(actor.id);
+        }
+        if is_suspended && self.suspended.insert(actor.id) {
+            curr.suspended.push(actor.id);
+        } else if !is_suspended && self.suspended.remove(&actor.id) {
+            curr.resumed.push(actor.id);
+        }
+    }
+
+    /// Stops tracking actors that disappeared without being despawned
+    pub fn forget_missing(&mut self, present: &HashSet<Id>) {
+        self.known.retain(|id| present.contains(id));
+        self.marked.retain(|id| present.contains(id));
+        self.suspended.retain(|id| present.contains(id));
+    }
+
+    pub fn applied(&mut self, id: &Id, input: &InputMsg) {
+        if let Some(ref mut curr) = self.curr {
+            curr.inputs.push((*id, input.clone()));
+        }
+    }
+
+    pub fn despawned(&mut self, id: &Id) {
+        if self.is_enabled() {
+            self.removed.push(*id);
+        }
+    }
+
+    pub fn finish(&mut self, tick: SeqId, actors: Snapshot) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.flush_tick(None);
+        self.write(&Entry::End(EndRecord { tick, actors }));
+        if let Some(ref mut out) = self.out {
+            if let Err(err) = out.flush() {
+                println!("Failed to write replay: {}", err);
+            }
+        }
+        self.out = None;
+    }
+
+    fn flush_tick(&mut self, checksum: Option<Checksum>) {
+        if let Some(mut record) = self.curr.take() {
+            if let Some(checksum) = checksum {
+                if checksum.tick == record.tick {
+                    record.checksum = Some(checksum.value);
+                }
+            }
+            self.write(&Entry::Tick(record));
+        }
+    }
+
+    fn write(&mut self, entry: &Entry) {
+        let result = match self.out {
+            Some(ref mut out) => {
+                serde_json::to_writer(&mut *out, entry)
+                    .map_err(io::Error::from)
+                    .and_then(|_| out.write_all(b"\n"))
+            }
+            None => return,
+        };
+        // A broken recording shouldn't take the room down with it
+        if let Err(err) = result {
+            println!("Failed to write replay, stopping to record: {}", err);
+            self.out = None;
+        }
+    }
+}
+
+pub struct Replay {
+    pub header: Header,
+    pub ticks: Vec<TickRecord>,
+    /// Missing if the server didn't shut the room down cleanly
+    pub end: Option<EndRecord>,
+}
+
+#[derive(Debug)]
+pub enum Mismatch {
+    Checksum {
+        tick: SeqId,
+        expected: u32,
+        actual: u32,
+    },
+    FinalState { tick: SeqId },
+}
+
+impl Replay {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| err.to_string())?;
+        let mut header = None;
+        let mut ticks = Vec::new();
+        let mut end = None;
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|err| err.to_string())?;
+            let entry = serde_json::from_str(&line).map_err(|err| {
+                format!("Line {}: {}", number + 1, err)
+            })?;
+            match entry {
+                Entry::Header(entry) => header = Some(entry),
+                Entry::Tick(entry) => ticks.push(entry),
+                Entry::End(entry) => end = Some(entry),
+            }
+        }
+        let header = header.ok_or_else(|| "Missing header".to_string())?;
+        if header.version != REPLAY_VERSION {
+            return Err(format!(
+                "Incompatible replay version {}, expected {}",
+                header.version,
+                REPLAY_VERSION
+            ));
+        }
+        // Ticks are timed and divided by this rate, it has to fit the per second math
+        if header.updates_per_sec == 0 || header.updates_per_sec > i32::max_value() as u64 {
+            return Err(format!("Invalid update rate {}", header.updates_per_sec));
+        }
+        Ok(Replay { header, ticks, end })
+    }
+
+    /// Simulates the recorded ticks again, calling `on_tick` with the state after each one.
+    /// Stops at the first checksum that doesn't match the recorded one.
+    pub fn run<F>(&self, mut on_tick: F) -> Result<(), Mismatch>
+    where
+        F: FnMut(SeqId, &Snapshot),
+    {
+        let mut world = World::new();
+        let inputs: InputMap = Arc::new(RwLock::new(HashMap::new()));
+        self.prepare_world(&mut world, &inputs);
+        // Bots don't need to run, their inputs have been recorded like everyone else's
+        let mut updater = DispatcherBuilder::new()
+            .add(Lifecycle, "lifecycle", &[])
+            .add(InputHandler, "input_handler", &["lifecycle"])
+            .add(Spawn, "spawn", &["input_handler"])
+            .add(Physics, "physics", &["spawn"])
+            .add(Bounce, "bounce", &["physics"])
+            .build();
+        let mut despawner = DispatcherBuilder::new()
+            .add(Despawn, "despawn", &[])
+            .build();
+
+        let mut id_entity: HashMap<Id, Entity> = HashMap::new();
+        let mut actors = Snapshot::new();
+        for record in &self.ticks {
+            if !record.removed.is_empty() {
+                for id in &record.removed {
+                    if let Some(entity) = id_entity.remove(id) {
+                        world.write::<ToDespawn>().insert(entity, ToDespawn {});
+                    }
+                }
+                despawner.dispatch(&mut world.res);
+            }
+            for spawn in &record.spawned {
+                let actor = Actor {
+                    id: spawn.id,
+                    kind: spawn.kind.clone(),
+                };
+                let entity = match spawn.difficulty {
+                    Some(ref difficulty) => {
+                        world
+                            .create_entity()
+                            .with(ToSpawn {})
+                            .with(Bot::new(difficulty.clone(), &spawn.id))
+                            .with(actor)
+                            .build()
+                    }
+                    None => world.create_entity().with(ToSpawn {}).with(actor).build(),
+                };
+                id_entity.insert(spawn.id, entity);
+            }
+            for id in &record.marked {
+                if let Some(entity) = id_entity.get(id) {
+                    world.write::<ToDespawn>().insert(*entity, ToDespawn {});
+                }
+            }
+            for id in &record.suspended {
+                if let Some(entity) = id_entity.get(id) {
+                    world.write::<Suspended>().insert(*entity, Suspended { strikes: 0 });
+                }
+            }
+            for id in &record.resumed {
+                if let Some(entity) = id_entity.get(id) {
+                    world.write::<Suspended>().remove(*entity);
+                }
+            }
+            {
+                let mut inputs = inputs.write().unwrap();
+                for &(ref id, ref input) in &record.inputs {
+                    inputs.entry(*id).or_insert_with(JitterBuffer::default).push(
+                        input.clone(),
+                    );
+                }
+            }
+
+            world.add_resource(record.tick);
+            updater.dispatch(&mut world.res);
+            world.maintain();
+
+            actors = snapshot(&world);
+            if let Some(expected) = record.checksum {
+                let actual = hash_actors(actors.iter().map(|(id, state)| {
+                    (id, &*state.pos, &*state.vel)
+                }));
+                if actual != expected {
+                    return Err(Mismatch::Checksum {
+                        tick: record.tick,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+            on_tick(record.tick, &actors);
+        }
+
+        match self.end {
+            Some(ref end) if end.actors != actors => Err(Mismatch::FinalState { tick: end.tick }),
+            _ => Ok(()),
+        }
+    }
+
+    fn prepare_world(&self, world: &mut World, inputs: &InputMap) {
+        let header = &self.header;
+        bootstrap::prepare_world(world, &header.game);
+        world.add_resource(inputs.clone());
+        world.add_resource(RwLock::new(CollisionWorld::<Id>::with_cell_size(
+            header.game.arena.width,
+            header.game.arena.height,
+            header.game.cell_size,
+        )));
+        world.add_resource(RwLock::new(History::<Id>::new(header.max_rewind_ticks)));
+        world.add_resource(LagCompensation { max_rewind_ticks: header.max_rewind_ticks });
+        world.add_resource(DeltaTime { ticks_per_sec: header.updates_per_sec as u32 });
+        world.add_resource(RwLock::new(MatchState::new(
+            header.score_limit,
+            header.countdown_ticks,
+            header.game_over_ticks,
+        )));
+        let recorder: SharedRecorder = Arc::new(RwLock::new(Recorder::disabled()));
+        world.add_resource(recorder);
+    }
+}
+
+/// Position and velocity of every actor that has been spawned
+pub fn snapshot(world: &World) -> Snapshot {
+    let actor = world.read::<Actor>();
+    let pos = world.read::<Pos>();
+    let vel = world.read::<Vel>();
+    (&actor, &pos, &vel)
+        .join()
+        .map(|(actor, pos, vel)| {
+            let state = ActorState {
+                pos: pos.clone(),
+                vel: vel.clone(),
+            };
+            (actor.id, state)
+        })
+        .collect()
+}
@@ -0,0 +1,1137 @@
+extern crate specs;
+extern crate chrono;
+extern crate websocket;
+extern crate websocket_server;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate uuid;
+
+use self::specs::{DispatcherBuilder, Dispatcher, World, Entity, Join};
+use self::chrono::prelude::*;
+use self::websocket::CloseData;
+use self::websocket_server::{SendChannel, Message};
+use self::futures::{Future, Sink};
+use self::futures_cpupool::CpuPool;
+use self::uuid::Uuid;
+
+use util::{elapsed_ms, timestamp, IdGen, SeqId, SeqIdGen, Time, RateLimiter, Ticker};
+use model::comp::{ToSpawn, ToDespawn, Player, Actor, ActorKind, Difficulty, Suspended, Resumed};
+use model::network::{InputMsg, JitterBuffer, Format, RoomRequest, Pong, Chat, Offense,
+                     MAX_CHAT_LEN};
+use model::game::{Id, MatchState, RoomCode, BotConfig, LagCompensation, DeltaTime, Checksum,
+                  SessionToken, PLAYERS_PER_MATCH};
+use system::*;
+use bootstrap;
+use collision::{World as CollisionWorld, History};
+use outbox::{Outbox, OutboxStats};
+use config::GameConfig;
+use replay::{self, Recorder, SharedRecorder, Header};
+use error::Error;
+
+use std::sync::{Arc, RwLock, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::RefCell;
+use std::thread::{self, sleep};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+
+/// Close code for clients that don't speak our protocol
+const PROTOCOL_ERROR: u16 = 1002;
+/// Seeded id generators draw from separate streams so they never hand out the same id
+const CLIENT_ID_STREAM: u64 = 0;
+const ROOM_ID_STREAM: u64 = 1;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RoomConfig {
+    /// How often the world is simulated
+    pub updates_per_sec: u64,
+    /// How often clients are told about the world, at most once per update
+    pub sends_per_sec: u64,
+    pub score_limit: u32,
+    pub countdown_secs: u64,
+    pub game_over_secs: u64,
+    pub bot_timeout_secs: u64,
+    pub bot_difficulty: Difficulty,
+    pub outbox_capacity: usize,
+    pub max_rewind_ms: u64,
+    /// Makes every room simulate deterministically, equal inputs lead to equal states
+    pub seed: Option<u64>,
+    /// Every room writes a replay file into this directory if set
+    pub replay_dir: Option<PathBuf>,
+    /// How long the place of a client with a dropped connection is kept
+    pub session_grace_secs: u64,
+    /// Larger messages get the client kicked right away
+    pub max_message_bytes: usize,
+    /// Messages above this rate are dropped and cost a strike
+    pub max_messages_per_sec: u32,
+    /// Clients get kicked once they collected this many strikes
+    pub max_strikes: u32,
+    pub game: GameConfig,
+}
+
+/// Same as the example config
+impl Default for RoomConfig {
+    fn default() -> Self {
+        RoomConfig {
+            updates_per_sec: 30,
+            sends_per_sec: 30,
+            score_limit: 11,
+            countdown_secs: 3,
+            game_over_secs: 5,
+            bot_timeout_secs: 10,
+            bot_difficulty: Difficulty::default(),
+            outbox_capacity: 64,
+            max_rewind_ms: 200,
+            seed: None,
+            replay_dir: None,
+            session_grace_secs: 30,
+            max_message_bytes: 1024,
+            max_messages_per_sec: 120,
+            max_strikes: 10,
+            game: GameConfig::default(),
+        }
+    }
+}
+
+impl RoomConfig {
+    pub fn is_deterministic(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    pub fn ms_per_tick(&self) -> Time {
+        1000 / self.updates_per_sec
+    }
+
+    pub fn delta_time(&self) -> DeltaTime {
+        DeltaTime { ticks_per_sec: self.updates_per_sec as u32 }
+    }
+
+    pub fn countdown_ticks(&self) -> u32 {
+        (self.countdown_secs * self.updates_per_sec) as u32
+    }
+
+    pub fn game_over_ticks(&self) -> u32 {
+        (self.game_over_secs * self.updates_per_sec) as u32
+    }
+
+    pub fn max_rewind_ticks(&self) -> usize {
+        (self.max_rewind_ms / self.ms_per_tick()) as usize
+    }
+
+    /// Generates the ids of new connections
+    pub fn client_ids(&self) -> IdGen {
+        self.id_gen(CLIENT_ID_STREAM)
+    }
+
+    fn id_gen(&self, stream: u64) -> IdGen {
+        match self.seed {
+            Some(seed) => IdGen::seeded(seed, stream),
+            None => IdGen::Random,
+        }
+    }
+}
+
+/// The world of a room together with the systems moving it forward
+pub struct RoomWorld {
+    pub world: World,
+    updater: Dispatcher<'static, 'static>,
+    sender: Dispatcher<'static, 'static>,
+    curr_tick_generator: SeqIdGen,
+    curr_tick: SeqId,
+    /// Counts updates, sending whenever a tick of its own is due
+    send_schedule: Ticker,
+    recorder: SharedRecorder,
+}
+
+impl RoomWorld {
+    pub fn curr_tick(&self) -> SeqId {
+        self.curr_tick
+    }
+
+    /// Simulates a single tick, returns whether it is time to send again
+    pub fn update(&mut self) -> bool {
+        self.curr_tick = self.curr_tick_generator.gen();
+        self.world.add_resource(self.curr_tick);
+        self.updater.dispatch(&mut self.world.res);
+        self.world.maintain();
+        self.send_schedule.advance(1) > 0
+    }
+
+    /// Tells every client what happened since the last call
+    pub fn send(&mut self) {
+        self.sender.dispatch(&mut self.world.res);
+    }
+
+    pub fn finish(self) {
+        self.recorder.write().unwrap().finish(
+            self.curr_tick,
+            replay::snapshot(&self.world),
+        );
+    }
+}
+
+/// What a room needs to talk to a client
+type Connection = (SendChannel, Format, SessionToken);
+
+thread_local! {
+    /// Where the room whose tick loop runs on this thread keeps its crash
+    static CRASH: RefCell<Option<Arc<RwLock<Option<Error>>>>> = RefCell::new(None);
+}
+
+static CRASH_HOOK: Once = ONCE_INIT;
+
+/// Marks a room as crashed as soon as its tick loop panics.
+/// Unwinding poisons the locks it holds, nobody should touch them after that.
+fn install_crash_hook() {
+    CRASH_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            CRASH.with(|crash| if let Some(ref crash) = *crash.borrow() {
+                // Blocking while panicking is asking for a deadlock
+                if let Ok(mut crash) = crash.try_write() {
+                    *crash = Some(Error::from_panic(info.payload()));
+                }
+            });
+            default_hook(info);
+        }));
+    });
+}
+
+/// A single match with its own world and tick loop
+pub struct Room {
+    code: RoomCode,
+    connected: RwLock<HashSet<Id>>,
+    id_entity: RwLock<HashMap<Id, Entity>>,
+    to_spawn: RwLock<HashMap<Id, Connection>>,
+    to_suspend: RwLock<HashSet<Id>>,
+    to_resume: RwLock<HashMap<Id, Connection>>,
+    acks: RwLock<HashMap<Id, SeqId>>,
+    pongs: RwLock<Vec<(Id, u32, Time)>>,
+    to_despawn: RwLock<HashSet<Id>>,
+    to_strike: RwLock<Vec<(Id, Offense)>>,
+    offenders: RwLock<HashSet<Id>>,
+    /// Players the room got rid of on its own while they were still connected
+    lost: RwLock<Vec<Id>>,
+    /// Players whose connection got closed for falling behind
+    fell_behind: RwLock<Vec<Id>>,
+    inputs: InputMap,
+    pings: PingQueue,
+    chat: ChatQueue,
+    dropped: DroppedClients,
+    latencies: Latencies,
+    outbox_stats: Arc<RwLock<OutboxStats>>,
+    pool: CpuPool,
+    is_closed: AtomicBool,
+    crash: Arc<RwLock<Option<Error>>>,
+}
+
+impl Room {
+    pub fn new(code: RoomCode, pool: CpuPool) -> Self {
+        Room {
+            code,
+            connected: RwLock::new(HashSet::new()),
+            id_entity: RwLock::new(HashMap::new()),
+            to_spawn: RwLock::new(HashMap::new()),
+            to_suspend: RwLock::new(HashSet::new()),
+            to_resume: RwLock::new(HashMap::new()),
+            acks: RwLock::new(HashMap::new()),
+            pongs: RwLock::new(Vec::new()),
+            to_despawn: RwLock::new(HashSet::new()),
+            to_strike: RwLock::new(Vec::new()),
+            offenders: RwLock::new(HashSet::new()),
+            lost: RwLock::new(Vec::new()),
+            fell_behind: RwLock::new(Vec::new()),
+            inputs: Arc::new(RwLock::new(HashMap::new())),
+            pings: Arc::new(RwLock::new(Vec::new())),
+            chat: Arc::new(RwLock::new(Vec::new())),
+            dropped: Arc::new(RwLock::new(Vec::new())),
+            latencies: Arc::new(RwLock::new(HashMap::new())),
+            outbox_stats: Arc::new(RwLock::new(OutboxStats::default())),
+            pool,
+            is_closed: AtomicBool::new(false),
+            crash: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn code(&self) -> &RoomCode {
+        &self.code
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.connected.read().unwrap().len()
+    }
+
+    pub fn has_free_slot(&self) -> bool {
+        self.player_count() < PLAYERS_PER_MATCH
+    }
+
+    /// Whether the player still has a place here, even if its connection is gone
+    pub fn has_player(&self, id: &Id) -> bool {
+        self.connected.read().unwrap().contains(id)
+    }
+
+    pub fn join(&self, id: Id, send_channel: SendChannel, format: Format, session: SessionToken) {
+        self.connected.write().unwrap().insert(id);
+        self.to_spawn.write().unwrap().insert(
+            id,
+            (send_channel, format, session),
+        );
+        println!("Room {}: Client {} joined", self.code, id);
+    }
+
+    pub fn leave(&self, id: &Id) {
+        self.connected.write().unwrap().remove(id);
+        self.inputs.write().unwrap().remove(id);
+        self.acks.write().unwrap().remove(id);
+        self.to_suspend.write().unwrap().remove(id);
+        self.to_resume.write().unwrap().remove(id);
+        self.to_despawn.write().unwrap().insert(*id);
+        println!("Room {}: Client {} left", self.code, id);
+    }
+
+    /// Keeps the client's place while its connection is gone, until it resumes or leaves
+    pub fn suspend(&self, id: &Id) {
+        self.inputs.write().unwrap().remove(id);
+        self.acks.write().unwrap().remove(id);
+        self.to_resume.write().unwrap().remove(id);
+        self.to_suspend.write().unwrap().insert(*id);
+        println!("Room {}: Client {} dropped, keeping its place", self.code, id);
+    }
+
+    /// Lets a suspended client carry on over a new connection.
+    /// Returns false if the room got rid of the player in the meantime.
+    pub fn resume(
+        &self,
+        id: &Id,
+        send_channel: SendChannel,
+        format: Format,
+        session: SessionToken,
+    ) -> bool {
+        if !self.has_player(id) {
+            return false;
+        }
+        self.to_suspend.write().unwrap().remove(id);
+        self.to_resume.write().unwrap().insert(
+            *id,
+            (send_channel, format, session),
+        );
+        println!("Room {}: Client {} resumed", self.code, id);
+        true
+    }
+
+    pub fn push_input(&self, id: &Id, key_state: InputMsg) {
+        let mut inputs = self.inputs.write().unwrap();
+        let buffer = inputs.entry(*id).or_insert_with(JitterBuffer::default);
+        if !buffer.push(key_state) {
+            println!("Room {}: Dropped input of client {}: Too many pending", self.code, id);
+        }
+    }
+
+    pub fn push_ack(&self, id: &Id, tick: SeqId) {
+        let mut acks = self.acks.write().unwrap();
+        let acked = acks.entry(*id).or_insert(tick);
+        // Acks can arrive out of order
+        if tick > *acked {
+            *acked = tick;
+        }
+    }
+
+    pub fn push_pong(&self, id: &Id, ping_id: u32) {
+        // Timestamp it now, the tick loop might be sleeping
+        self.pongs.write().unwrap().push((*id, ping_id, timestamp()));
+    }
+
+    pub fn push_ping(&self, id: &Id, ping_id: u32) {
+        self.pings.write().unwrap().push((*id, Pong { id: ping_id }));
+    }
+
+    pub fn push_chat(&self, id: &Id, mut text: String) {
+        if let Some((cutoff, _)) = text.char_indices().nth(MAX_CHAT_LEN) {
+            text.truncate(cutoff);
+        }
+        self.chat.write().unwrap().push(Chat { from: *id, text });
+    }
+
+    /// Counts against the client the next time connections are registered
+    pub fn strike(&self, id: &Id, offense: Offense) {
+        self.to_strike.write().unwrap().push((*id, offense));
+    }
+
+    /// Clients that collected too many strikes since the last call
+    pub fn take_offenders(&self) -> Vec<Id> {
+        self.offenders.write().unwrap().drain().collect()
+    }
+
+    /// Players the room got rid of on its own since the last call
+    pub fn take_lost(&self) -> Vec<Id> {
+        self.lost.write().unwrap().drain(..).collect()
+    }
+
+    /// Players dropped for falling behind since the last call
+    pub fn take_fell_behind(&self) -> Vec<Id> {
+        self.fell_behind.write().unwrap().drain(..).collect()
+    }
+
+    /// Round trip time to the client as of the last tick, if we measured one yet
+    pub fn rtt(&self, id: &Id) -> Option<Time> {
+        self.latencies.read().unwrap().get(id).cloned()
+    }
+
+    pub fn outbox_stats(&self) -> OutboxStats {
+        self.outbox_stats.read().unwrap().clone()
+    }
+
+    /// Makes the tick loop return after its current iteration
+    pub fn close(&self) {
+        self.is_closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Runs the tick loop, remembering why it panicked instead of unwinding any further
+    pub fn supervise(&self, config: &RoomConfig) {
+        install_crash_hook();
+        CRASH.with(|crash| *crash.borrow_mut() = Some(self.crash.clone()));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.run(config)));
+        CRASH.with(|crash| *crash.borrow_mut() = None);
+        if let Err(payload) = result {
+            let mut crash = self.crash.write().unwrap();
+            // Only if the hook couldn't get to the lock
+            if crash.is_none() {
+                *crash = Some(Error::from_panic(&*payload));
+            }
+        }
+    }
+
+    pub fn has_crashed(&self) -> bool {
+        self.crash.read().unwrap().is_some()
+    }
+
+    pub fn crash(&self) -> Option<Error> {
+        self.crash.read().unwrap().clone()
+    }
+
+    pub fn run(&self, config: &RoomConfig) {
+        let mut room_world = self.start(config);
+        let mut clock = Ticker::new(config.updates_per_sec, 1000);
+        let mut previous = Utc::now();
+        while !self.is_closed.load(Ordering::SeqCst) {
+            let current = Utc::now();
+            // The clock might have been adjusted, carry on as if no time passed
+            let elapsed = elapsed_ms(previous, current).unwrap_or(0);
+            previous = current;
+
+            let curr_tick = room_world.curr_tick();
+            self.register_connections(&mut room_world.world, curr_tick, config);
+            // Every update is a tick of its own, so inputs can be applied in the right one
+            let mut is_send_due = false;
+            for _ in 0..clock.advance(elapsed) {
+                is_send_due |= room_world.update();
+            }
+            // Catching up on several updates still only sends the latest state
+            if is_send_due {
+                room_world.send();
+                self.forget_dropped_clients();
+            }
+
+            sleep(Duration::from_millis(clock.until_next()));
+        }
+        room_world.finish();
+    }
+
+    /// Sets up the world and runs the initial update
+    pub fn start(&self, config: &RoomConfig) -> RoomWorld {
+        let mut world = World::new();
+        let recorder = self.create_recorder(config);
+        self.prepare_world(&mut world, config, &recorder);
+
+        let mut updater = DispatcherBuilder::new()
+            .add(Lifecycle, "lifecycle", &[])
+            .add(Bots, "bots", &["lifecycle"])
+            .add(Recording, "recording", &["bots"])
+            .add(InputHandler, "input_handler", &["recording"])
+            .add(Spawn, "spawn", &["input_handler"])
+            .add(Physics, "physics", &["spawn"])
+            .add(Bounce, "bounce", &["physics"]);
+        if config.is_deterministic() {
+            updater = updater.add(Checksums, "checksums", &["bounce"]);
+        }
+        let sender = DispatcherBuilder::new()
+            .add(Sending, "sending", &[])
+            .add(Despawn, "despawn", &["sending"])
+            .build();
+
+        let mut curr_tick_generator = SeqIdGen::default();
+        let curr_tick = curr_tick_generator.gen();
+        world.add_resource(curr_tick);
+        let mut updater = updater.build();
+        updater.dispatch(&mut world.res);
+        RoomWorld {
+            world,
+            updater,
+            sender,
+            curr_tick_generator,
+            curr_tick,
+            send_schedule: Ticker::new(config.sends_per_sec, config.updates_per_sec),
+            recorder,
+        }
+    }
+
+    pub fn entity(&self, id: &Id) -> Option<Entity> {
+        self.id_entity.read().unwrap().get(id).cloned()
+    }
+
+    fn create_recorder(&self, config: &RoomConfig) -> SharedRecorder {
+        let recorder = match config.replay_dir {
+            Some(ref dir) => {
+                let path = dir.join(format!("{}.replay", self.code));
+                match Recorder::create(&path, Header::new(&self.code, config)) {
+                    Ok(recorder) => {
+                        println!("Room {}: Recording to {}", self.code, path.display());
+                        recorder
+                    }
+                    Err(err) => {
+                        println!("Room {}: Failed to create replay file: {}", self.code, err);
+                        Recorder::disabled()
+                    }
+                }
+            }
+            None => Recorder::disabled(),
+        };
+        Arc::new(RwLock::new(recorder))
+    }
+
+    fn prepare_world(&self, world: &mut World, config: &RoomConfig, recorder: &SharedRecorder) {
+        bootstrap::prepare_world(world, &config.game);
+        world.add_resource(recorder.clone());
+        world.add_resource(self.inputs.clone());
+        world.add_resource(self.pings.clone());
+        world.add_resource(self.chat.clone());
+        world.add_resource(self.dropped.clone());
+        world.add_resource(self.latencies.clone());
+        world.add_resource(self.outbox_stats.clone());
+        world.add_resource(self.code.clone());
+        world.add_resource(RwLock::new(CollisionWorld::<Id>::with_cell_size(
+            config.game.arena.width,
+            config.game.arena.height,
+            config.game.cell_size,
+        )));
+        world.add_resource(RwLock::new(None::<Checksum>));
+        let mut id_gen = config.id_gen(ROOM_ID_STREAM);
+        let max_rewind_ticks = config.max_rewind_ticks();
+        world.add_resource(RwLock::new(History::<Id>::new(max_rewind_ticks)));
+        world.add_resource(LagCompensation { max_rewind_ticks });
+        world.add_resource(config.delta_time());
+        world.add_resource(RwLock::new(MatchState::new(
+            config.score_limit,
+            config.countdown_ticks(),
+            config.game_over_ticks(),
+        )));
+        world.add_resource(BotConfig {
+            fill_timeout_ticks: (config.bot_timeout_secs * config.updates_per_sec) as u32,
+            difficulty: config.bot_difficulty.clone(),
+        });
+
+        // Create ball
+        let id = id_gen.gen();
+        let entity = world
+            .create_entity()
+            .with(ToSpawn {})
+            .with(Actor {
+                id,
+                kind: ActorKind::Ball,
+            })
+            .build();
+        self.id_entity.write().unwrap().insert(id, entity);
+        world.add_resource(RwLock::new(id_gen));
+    }
+
+    /// Lets new clients into the world and applies what they sent since the last call
+    pub fn register_connections(
+        &self,
+        world: &mut World,
+        curr_tick: SeqId,
+        config: &RoomConfig,
+    ) {
+        let mut id_entity = self.id_entity.write().unwrap();
+        let mut to_spawn = self.to_spawn.write().unwrap();
+        for (id, (send_channel, format, session)) in to_spawn.drain() {
+            let outbox = Outbox::new(send_channel, config.outbox_capacity, &self.pool);
+            let entity = world
+                .create_entity()
+                .with(ToSpawn {})
+                .with(Player::new(outbox, format, session))
+                .with(Actor {
+                    id,
+                    kind: ActorKind::Player,
+                })
+                .build();
+            id_entity.insert(id, entity);
+        }
+
+        // Without a player nobody sends to the dead connection, but the paddle stays
+        let mut to_suspend = self.to_suspend.write().unwrap();
+        for id in to_suspend.drain() {
+            if let Some(entity) = id_entity.get(&id) {
+                let strikes = world.write::<Player>().remove(*entity).map_or(
+                    0,
+                    |player| player.strikes,
+                );
+                world.write::<Suspended>().insert(*entity, Suspended { strikes });
+            }
+        }
+
+        let mut to_resume = self.to_resume.write().unwrap();
+        for (id, (send_channel, format, session)) in to_resume.drain() {
+            let entity = match id_entity.get(&id) {
+                Some(entity) => *entity,
+                None => {
+                    // Dropped right before it came back, it needs a fresh place
+                    self.lost.write().unwrap().push(id);
+                    continue;
+                }
+            };
+            let outbox = Outbox::new(send_channel, config.outbox_capacity, &self.pool);
+            let mut player = Player::new(outbox, format, session);
+            if let Some(suspended) = world.write::<Suspended>().remove(entity) {
+                player.strikes = suspended.strikes;
+            }
+            world.write::<Player>().insert(entity, player);
+            world.write::<Resumed>().insert(entity, Resumed {});
+        }
+
+        let mut to_strike = self.to_strike.write().unwrap();
+        let mut acks = self.acks.write().unwrap();
+        for (id, tick) in acks.drain() {
+            // Nothing past the current tick has been sent yet
+            if tick > curr_tick {
+                to_strike.push((id, Offense::FutureAck));
+                continue;
+            }
+            if let Some(entity) = id_entity.get(&id) {
+                if let Some(player) = world.write::<Player>().get_mut(*entity) {
+                    if player.acked_tick.map_or(true, |acked| tick > acked) {
+                        player.acked_tick = Some(tick);
+                    }
+                }
+            }
+        }
+
+        let mut pongs = self.pongs.write().unwrap();
+        for (id, ping_id, received) in pongs.drain(..) {
+            if let Some(entity) = id_entity.get(&id) {
+                if let Some(player) = world.write::<Player>().get_mut(*entity) {
+                    player.latency.finish_ping(ping_id, received);
+                }
+            }
+        }
+
+        for (id, offense) in to_strike.drain(..) {
+            if let Some(entity) = id_entity.get(&id) {
+                if let Some(player) = world.write::<Player>().get_mut(*entity) {
+                    player.strikes += 1;
+                    println!("Client {}: Strike {} for {}", id, player.strikes, offense);
+                }
+            }
+        }
+        // Strikes also come from the systems, so look at everyone
+        let mut offenders = self.offenders.write().unwrap();
+        for (actor, player) in (&world.read::<Actor>(), &world.read::<Player>()).join() {
+            if player.strikes >= config.max_strikes {
+                offenders.insert(actor.id);
+            }
+        }
+
+        let mut to_despawn = self.to_despawn.write().unwrap();
+        for id in to_despawn.drain() {
+            if let Some(entity) = id_entity.remove(&id) {
+                world.write::<ToDespawn>().insert(entity, ToDespawn {});
+            }
+        }
+    }
+
+    /// Sending already despawned them, we only need to stop tracking them
+    /// and let the RoomManager know
+    pub fn forget_dropped_clients(&self) {
+        let dropped: Vec<Id> = self.dropped.write().unwrap().drain(..).collect();
+        for id in dropped {
+            self.connected.write().unwrap().remove(&id);
+            self.inputs.write().unwrap().remove(&id);
+            self.acks.write().unwrap().remove(&id);
+            self.id_entity.write().unwrap().remove(&id);
+            self.fell_behind.write().unwrap().push(id);
+            println!("Room {}: Dropped client {} for falling behind", self.code, id);
+        }
+    }
+}
+
+struct Client {
+    send_channel: SendChannel,
+    /// Only known once the client said hello
+    format: Option<Format>,
+    /// Id of the client's actor, which stays the same when it resumes a session
+    player: Id,
+    session: SessionToken,
+    room: Option<RoomCode>,
+    limiter: RateLimiter,
+}
+
+/// A client whose connection dropped while it was in a room
+struct Suspension {
+    player: Id,
+    room: RoomCode,
+    since: Time,
+}
+
+/// Keeps track of all running rooms and which client plays in which one
+pub struct RoomManager {
+    config: RoomConfig,
+    pool: CpuPool,
+    rooms: RwLock<HashMap<RoomCode, Arc<Room>>>,
+    clients: RwLock<HashMap<Id, Client>>,
+    sessions: RwLock<HashMap<SessionToken, Suspension>>,
+    ids: RwLock<IdGen>,
+}
+
+impl RoomManager {
+    pub fn new(config: RoomConfig) -> Self {
+        let ids = RwLock::new(config.client_ids());
+        RoomManager {
+            config,
+            pool: CpuPool::new_num_cpus(),
+            rooms: RwLock::new(HashMap::new()),
+            clients: RwLock::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+            ids,
+        }
+    }
+
+    pub fn generate_id(&self) -> Id {
+        self.ids.write().unwrap().gen()
+    }
+
+    /// New clients wait outside of any room until they said hello
+    pub fn connect(&self, id: Id, send_channel: SendChannel) {
+        self.clients.write().unwrap().insert(
+            id,
+            Client {
+                send_channel,
+                format: None,
+                player: id,
+                // Tokens guard sessions, so they stay random even in deterministic mode
+                session: Uuid::new_v4(),
+                room: None,
+                limiter: RateLimiter::new(self.config.max_messages_per_sec, timestamp()),
+            },
+        );
+    }
+
+    /// Checks a message against the limits before it gets parsed, kicking blatant offenders
+    pub fn admit(&self, id: &Id, size: usize) -> bool {
+        if size > self.config.max_message_bytes {
+            let reason = format!(
+                "Message of {} bytes exceeds the limit of {}",
+                size,
+                self.config.max_message_bytes
+            );
+            self.reject(id, &reason);
+            return false;
+        }
+        let is_allowed = match self.clients.write().unwrap().get_mut(id) {
+            Some(client) => client.limiter.allow(timestamp()),
+            None => return false,
+        };
+        if !is_allowed {
+            self.strike(id, Offense::Flooding);
+        }
+        is_allowed
+    }
+
+    /// Counts against the client's player. Outside of a room there is no player to keep
+    /// track of strikes, and no reason to misbehave, so the client gets kicked right away.
+    pub fn strike(&self, id: &Id, offense: Offense) {
+        let is_struck = self.with_room(id, |room, player| room.strike(player, offense));
+        if !is_struck {
+            self.reject(id, &format!("Kicked for {}", offense));
+        }
+    }
+
+    /// Kicks every client that collected too many strikes
+    pub fn kick_offenders(&self) {
+        let offenders: Vec<Id> = {
+            let rooms = self.rooms.read().unwrap();
+            let players: HashSet<Id> = rooms
+                .values()
+                .filter(|room| !room.has_crashed())
+                .flat_map(|room| room.take_offenders())
+                .collect();
+            self.clients
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|&(_, client)| players.contains(&client.player))
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        for id in offenders {
+            self.reject(&id, "Too many strikes");
+        }
+    }
+
+    /// Takes clients out of rooms that got rid of their players on their own.
+    /// Those still connected get a fresh place, those that fell behind are gone for good.
+    pub fn forget_lost_players(&self) {
+        let lost: Vec<Id> = {
+            let rooms = self.rooms.read().unwrap();
+            let mut clients = self.clients.write().unwrap();
+            let mut lost = Vec::new();
+            let mut fell_behind = Vec::new();
+            for room in rooms.values().filter(|room| !room.has_crashed()) {
+                let players: HashSet<Id> = room.take_lost().into_iter().collect();
+                let lagging: HashSet<Id> = room.take_fell_behind().into_iter().collect();
+                for (id, client) in clients.iter_mut() {
+                    if client.room.as_ref() != Some(room.code()) {
+                        continue;
+                    }
+                    if players.contains(&client.player) {
+                        client.room = None;
+                        lost.push(*id);
+                    } else if lagging.contains(&client.player) {
+                        client.room = None;
+                        fell_behind.push(*id);
+                    }
+                }
+            }
+            // Their outbox already closed the connection, there is nothing left to send to
+            for id in fell_behind {
+                clients.remove(&id);
+                println!("Client {}: Disconnected for falling behind", id);
+            }
+            lost
+        };
+        for id in lost {
+            println!("Client {}: Lost its place, joining another room", id);
+            self.request(&id, RoomRequest::Auto);
+        }
+    }
+
+    /// Clients in a room keep their place for a while, in case they come back
+    pub fn disconnect(&self, id: &Id) {
+        let rooms = self.rooms.read().unwrap();
+        let client = match self.clients.write().unwrap().remove(id) {
+            Some(client) => client,
+            None => return,
+        };
+        let code = match client.room {
+            Some(code) => code,
+            None => return,
+        };
+        if let Some(room) = running(&rooms, &code) {
+            // Without a place to come back to, a session would lead nowhere
+            if !room.has_player(&client.player) {
+                return;
+            }
+            room.suspend(&client.player);
+            let suspension = Suspension {
+                player: client.player,
+                room: code,
+                since: timestamp(),
+            };
+            self.sessions.write().unwrap().insert(
+                client.session,
+                suspension,
+            );
+        }
+    }
+
+    /// Gives up on suspended clients that didn't come back in time
+    pub fn expire_sessions(&self) {
+        let now = timestamp();
+        let grace_ms = self.config.session_grace_secs * 1000;
+        let expired: Vec<Suspension> = {
+            let mut sessions = self.sessions.write().unwrap();
+            let tokens: Vec<SessionToken> = sessions
+                .iter()
+                .filter(|&(_, suspension)| now.saturating_sub(suspension.since) >= grace_ms)
+                .map(|(token, _)| *token)
+                .collect();
+            tokens
+                .iter()
+                .filter_map(|token| sessions.remove(token))
+                .collect()
+        };
+        let rooms = self.rooms.read().unwrap();
+        for suspension in expired {
+            println!("Client {}: Session expired", suspension.player);
+            if let Some(room) = running(&rooms, &suspension.room) {
+                room.leave(&suspension.player);
+            }
+        }
+    }
+
+    /// Closes the connection, telling the client why
+    pub fn reject(&self, id: &Id, reason: &str) {
+        let rooms = self.rooms.read().unwrap();
+        let client = match self.clients.write().unwrap().remove(id) {
+            Some(client) => client,
+            None => return,
+        };
+        if let Some(room) = client.room.as_ref().and_then(|code| running(&rooms, code)) {
+            room.leave(&client.player);
+        }
+        println!("Client {}: Rejected: {}", id, reason);
+        let close_data = CloseData::new(PROTOCOL_ERROR, reason.to_string());
+        let close = client
+            .send_channel
+            .send(Message::Close(Some(close_data)))
+            .then(|_| Ok::<(), ()>(()));
+        self.pool.spawn(close).forget();
+    }
+
+    pub fn has_said_hello(&self, id: &Id) -> bool {
+        self.clients.read().unwrap().get(id).map_or(false, |client| {
+            client.format.is_some()
+        })
+    }
+
+    /// Resumes the given session if possible,
+    /// otherwise puts the client into a room with a free slot, opening one if needed
+    pub fn hello(&self, id: &Id, format: Format, session: Option<SessionToken>) {
+        match self.clients.write().unwrap().get_mut(id) {
+            Some(ref mut client) if client.format.is_none() => client.format = Some(format),
+            _ => return,
+        }
+        if let Some(session) = session {
+            if self.resume(id, session) {
+                return;
+            }
+        }
+        self.request(id, RoomRequest::Auto);
+    }
+
+    /// Joining a full room makes the client a spectator
+    pub fn request(&self, id: &Id, request: RoomRequest) {
+        let mut rooms = self.rooms.write().unwrap();
+        let current = self.clients.read().unwrap().get(id).and_then(
+            |client| client.room.clone(),
+        );
+        let code = match request {
+            RoomRequest::Create => self.open_room(&mut rooms),
+            RoomRequest::Existing(code) => {
+                if running(&rooms, &code).is_none() {
+                    println!("Client {}: Tried to join nonexistent room {}", id, code);
+                    return;
+                }
+                code
+            }
+            RoomRequest::Auto => {
+                match find_free_room(&rooms, current.as_ref()) {
+                    Some(code) => code,
+                    None => self.open_room(&mut rooms),
+                }
+            }
+        };
+        self.move_client(&rooms, id, Some(code));
+    }
+
+    pub fn leave(&self, id: &Id) {
+        let rooms = self.rooms.read().unwrap();
+        self.move_client(&rooms, id, None);
+    }
+
+    pub fn handle_input(&self, id: &Id, key_state: InputMsg) {
+        self.with_room(id, |room, player| room.push_input(player, key_state));
+    }
+
+    pub fn handle_ack(&self, id: &Id, tick: SeqId) {
+        self.with_room(id, |room, player| room.push_ack(player, tick));
+    }
+
+    pub fn handle_pong(&self, id: &Id, ping_id: u32) {
+        self.with_room(id, |room, player| room.push_pong(player, ping_id));
+    }
+
+    /// Round trip time to the client, if it is in a room and we measured one yet
+    pub fn rtt(&self, id: &Id) -> Option<Time> {
+        let rooms = self.rooms.read().unwrap();
+        let clients = self.clients.read().unwrap();
+        let client = clients.get(id)?;
+        client
+            .room
+            .as_ref()
+            .and_then(|code| running(&rooms, code))
+            .and_then(|room| room.rtt(&client.player))
+    }
+
+    pub fn handle_ping(&self, id: &Id, ping_id: u32) {
+        self.with_room(id, |room, player| room.push_ping(player, ping_id));
+    }
+
+    pub fn handle_chat(&self, id: &Id, text: String) {
+        self.with_room(id, |room, player| room.push_chat(player, text));
+    }
+
+    pub fn outbox_stats(&self) -> Vec<(RoomCode, OutboxStats)> {
+        self.rooms
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(code, room)| (code.clone(), room.outbox_stats()))
+            .collect()
+    }
+
+    /// Closes rooms whose tick loop crashed, their clients get moved into other rooms
+    pub fn remove_crashed_rooms(&self) {
+        let crashed: Vec<(RoomCode, Error)> = {
+            let mut rooms = self.rooms.write().unwrap();
+            let codes: Vec<RoomCode> = rooms
+                .values()
+                .filter(|room| room.has_crashed())
+                .map(|room| room.code().clone())
+                .collect();
+            codes
+                .into_iter()
+                .filter_map(|code| {
+                    let crash = rooms.remove(&code).and_then(|room| room.crash());
+                    crash.map(|err| (code, err))
+                })
+                .collect()
+        };
+        for (code, err) in crashed {
+            println!("Room {}: Closed: {}", code, err);
+            let stranded: Vec<Id> = self.clients
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|&(_, client)| client.room.as_ref() == Some(&code))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in stranded {
+                self.request(&id, RoomRequest::Auto);
+            }
+        }
+    }
+
+    pub fn close_empty_rooms(&self) {
+        let mut rooms = self.rooms.write().unwrap();
+        let empty: Vec<RoomCode> = rooms
+            .iter()
+            .filter(|&(_, room)| !room.has_crashed() && room.player_count() == 0)
+            .map(|(code, _)| code.clone())
+            .collect();
+        for code in empty {
+            if let Some(room) = rooms.remove(&code) {
+                room.close();
+                println!("Room {}: Closed", code);
+            }
+        }
+    }
+
+    /// Calls `f` with the client's room and the id of its actor in there,
+    /// returns whether the client is in a room at all
+    fn with_room<F>(&self, id: &Id, f: F) -> bool
+    where
+        F: FnOnce(&Room, &Id),
+    {
+        let rooms = self.rooms.read().unwrap();
+        let clients = self.clients.read().unwrap();
+        if let Some(client) = clients.get(id) {
+            if let Some(room) = client.room.as_ref().and_then(|code| running(&rooms, code)) {
+                f(room, &client.player);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Hands the actor of a suspended session over to the client
+    fn resume(&self, id: &Id, session: SessionToken) -> bool {
+        let rooms = self.rooms.read().unwrap();
+        let suspension = match self.sessions.write().unwrap().remove(&session) {
+            Some(suspension) => suspension,
+            None => return false,
+        };
+        let room = match running(&rooms, &suspension.room) {
+            Some(room) => room,
+            None => return false,
+        };
+        let mut clients = self.clients.write().unwrap();
+        let client = match clients.get_mut(id) {
+            Some(client) => client,
+            None => {
+                // Disconnected again before we got here, nobody is going to resume it now
+                room.leave(&suspension.player);
+                return false;
+            }
+        };
+        let format = client.format.unwrap_or_default();
+        let send_channel = client.send_channel.clone();
+        if !room.resume(&suspension.player, send_channel, format, session) {
+            println!("Client {}: Nothing left to resume", id);
+            return false;
+        }
+        client.player = suspension.player;
+        client.session = session;
+        client.room = Some(suspension.room);
+        println!("Client {}: Resumed as {}", id, client.player);
+        true
+    }
+
+    fn open_room(&self, rooms: &mut HashMap<RoomCode, Arc<Room>>) -> RoomCode {
+        let mut code = RoomCode::generate();
+        while rooms.contains_key(&code) {
+            code = RoomCode::generate();
+        }
+        let room = Arc::new(Room::new(code.clone(), self.pool.clone()));
+        let config = self.config.clone();
+        let runner = room.clone();
+        thread::spawn(move || runner.supervise(&config));
+        rooms.insert(code.clone(), room);
+        println!("Room {}: Opened", code);
+        code
+    }
+
+    fn move_client(&self, rooms: &HashMap<RoomCode, Arc<Room>>, id: &Id, code: Option<RoomCode>) {
+        let mut clients = self.clients.write().unwrap();
+        if let Some(client) = clients.get_mut(id) {
+            if client.room == code {
+                return;
+            }
+            if let Some(room) = client.room.as_ref().and_then(|code| running(&rooms, code)) {
+                room.leave(&client.player);
+            }
+            if let Some(ref code) = code {
+                let format = client.format.unwrap_or_default();
+                rooms[code].join(
+                    client.player,
+                    client.send_channel.clone(),
+                    format,
+                    client.session,
+                );
+            }
+            client.room = code;
+        }
+    }
+}
+
+fn find_free_room(
+    rooms: &HashMap<RoomCode, Arc<Room>>,
+    except: Option<&RoomCode>,
+) -> Option<RoomCode> {
+    rooms
+        .values()
+        .find(|room| {
+            !room.has_crashed() && room.has_free_slot() && Some(room.code()) != except
+        })
+        .map(|room| room.code().clone())
+}
+
+/// Rooms that crashed are never touched again, their locks might be poisoned
+fn running<'a>(rooms: &'a HashMap<RoomCode, Arc<Room>>, code: &RoomCode) -> Option<&'a Room> {
+    rooms.get(code).and_then(|room| if room.has_crashed() {
+        None
+    } else {
+        Some(&**room)
+    })
+}
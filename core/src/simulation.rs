@@ -0,0 +1,504 @@
+extern crate specs;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate websocket_server;
+extern crate serde_json;
+
+use self::specs::{Entity, World};
+use self::futures::{future, Async, Future, Stream};
+use self::futures::sync::mpsc::{self, Receiver};
+use self::futures_cpupool::CpuPool;
+use self::websocket_server::Message;
+
+use model::comp::Player;
+use model::game::{Id, RoomCode, SessionToken};
+use model::network::{ClientMessage, Format};
+use room::{Room, RoomConfig, RoomWorld};
+use util::{IdGen, SeqId};
+
+use std::collections::HashMap;
+use std::thread;
+
+/// Runs a room without any networking, one tick at a time.
+/// Meant for tests, whatever is sent to the fake players is kept in memory.
+pub struct Simulation {
+    config: RoomConfig,
+    room: Room,
+    room_world: RoomWorld,
+    ids: IdGen,
+    connections: HashMap<Id, Receiver<Message>>,
+    sessions: HashMap<Id, SessionToken>,
+    received: HashMap<Id, Vec<Message>>,
+}
+
+impl Simulation {
+    pub fn new(config: RoomConfig) -> Self {
+        let room = Room::new(RoomCode::generate(), CpuPool::new(1));
+        let room_world = room.start(&config);
+        let ids = config.client_ids();
+        Simulation {
+            config,
+            room,
+            room_world,
+            ids,
+            connections: HashMap::new(),
+            sessions: HashMap::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    /// Connects a player speaking JSON, it enters the world with the next step
+    pub fn add_player(&mut self) -> Id {
+        let id = self.ids.gen();
+        let session = self.ids.gen();
+        // A single tick never sends more than fits into an outbox
+        let (send_channel, receiver) = mpsc::channel(self.config.outbox_capacity);
+        self.room.join(id, send_channel, Format::Json, session);
+        self.connections.insert(id, receiver);
+        self.sessions.insert(id, session);
+        self.received.insert(id, Vec::new());
+        id
+    }
+
+    pub fn remove_player(&mut self, id: &Id) {
+        self.room.leave(id);
+    }
+
+    /// Loses the connection of the player, its paddle stays until it resumes
+    pub fn drop_connection(&mut self, id: &Id) {
+        self.room.suspend(id);
+        self.connections.remove(id);
+    }
+
+    /// Brings back a player whose connection dropped, like presenting its session would.
+    /// Returns false if the room has no place for it anymore.
+    pub fn resume(&mut self, id: &Id) -> bool {
+        let session = self.sessions[id];
+        let (send_channel, receiver) = mpsc::channel(self.config.outbox_capacity);
+        let is_resumed = self.room.resume(id, send_channel, Format::Json, session);
+        if is_resumed {
+            self.connections.insert(*id, receiver);
+        }
+        is_resumed
+    }
+
+    /// Handles the message like the server would if the player sent it right now
+    pub fn receive(&mut self, id: &Id, msg: ClientMessage) {
+        match msg {
+            ClientMessage::Input(input) => self.room.push_input(id, input),
+            ClientMessage::Ack(tick) => self.room.push_ack(id, tick),
+            ClientMessage::Ping { id: ping_id } => self.room.push_ping(id, ping_id),
+            ClientMessage::Pong { id: ping_id } => self.room.push_pong(id, ping_id),
+            ClientMessage::Chat(text) => self.room.push_chat(id, text),
+            ClientMessage::Leave => self.room.leave(id),
+            // There is only this one room and the player already said hello
+            ClientMessage::Hello { .. } |
+            ClientMessage::Join(_) => {}
+        }
+    }
+
+    /// Runs a single tick and sends its results right away, if a send is due
+    pub fn step(&mut self) {
+        let curr_tick = self.room_world.curr_tick();
+        self.room.register_connections(
+            &mut self.room_world.world,
+            curr_tick,
+            &self.config,
+        );
+        if self.room_world.update() {
+            self.room_world.send();
+            self.room.forget_dropped_clients();
+        }
+        self.collect_messages();
+    }
+
+    pub fn step_n(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            self.step();
+        }
+    }
+
+    pub fn curr_tick(&self) -> SeqId {
+        self.room_world.curr_tick()
+    }
+
+    pub fn code(&self) -> &RoomCode {
+        self.room.code()
+    }
+
+    pub fn config(&self) -> &RoomConfig {
+        &self.config
+    }
+
+    pub fn world(&self) -> &World {
+        &self.room_world.world
+    }
+
+    pub fn entity(&self, id: &Id) -> Option<Entity> {
+        self.room.entity(id)
+    }
+
+    /// Everything sent to the player since the last call
+    pub fn take_messages(&mut self, id: &Id) -> Vec<Message> {
+        self.received
+            .get_mut(id)
+            .map(|received| received.drain(..).collect())
+            .unwrap_or_default()
+    }
+
+    /// Like `take_messages`, but parsed back from JSON
+    pub fn take_json(&mut self, id: &Id) -> Vec<serde_json::Value> {
+        self.take_messages(id)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                Message::Text(text) => serde_json::from_str(&text).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Shuts the room down like the server would, which completes its replay
+    pub fn finish(self) {
+        self.room_world.finish();
+    }
+
+    fn collect_messages(&mut self) {
+        // Outboxes deliver on the thread pool, wait for them to catch up
+        loop {
+            let is_delivered = {
+                let player = self.room_world.world.read::<Player>();
+                self.connections.keys().all(|id| {
+                    self.room.entity(id).and_then(|entity| player.get(entity)).map_or(
+                        true,
+                        |player| player.outbox.depth() == 0,
+                    )
+                })
+            };
+            if is_delivered {
+                break;
+            }
+            thread::yield_now();
+        }
+        for (id, receiver) in &mut self.connections {
+            let received = self.received.entry(*id).or_insert_with(Vec::new);
+            while let Some(msg) = try_recv(receiver) {
+                received.push(msg);
+            }
+        }
+    }
+}
+
+fn try_recv(receiver: &mut Receiver<Message>) -> Option<Message> {
+    let poll = future::poll_fn(|| Ok::<_, ()>(Async::Ready(receiver.poll()))).wait();
+    match poll {
+        Ok(Ok(Async::Ready(msg))) => msg,
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use simulation::specs::Join;
+    use model::comp::{Bot, Bounciness, Paddle, Pos, Suspended};
+    use model::network::{Command, InputMsg};
+    use replay::Replay;
+    use std::env;
+    use std::fs;
+
+    fn opcodes(messages: &[serde_json::Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter_map(|msg| msg["opcode"].as_str())
+            .collect()
+    }
+
+    fn pos(sim: &Simulation, id: &Id) -> Pos {
+        let entity = sim.entity(id).expect("Player has no entity");
+        sim.world().read::<Pos>().get(entity).cloned().expect(
+            "Player has no position",
+        )
+    }
+
+    fn ball_pos(sim: &Simulation) -> Pos {
+        let pos = sim.world().read::<Pos>();
+        let bounciness = sim.world().read::<Bounciness>();
+        let (pos, _) = (&pos, &bounciness).join().next().expect("There is no ball");
+        pos.clone()
+    }
+
+    #[test]
+    fn new_player_is_greeted() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let id = sim.add_player();
+        sim.step();
+        let messages = sim.take_json(&id);
+        assert_eq!(Some(&"Greeting"), opcodes(&messages).first());
+        assert_eq!(id.to_string(), messages[0]["payload"]["id"]);
+    }
+
+    #[test]
+    fn world_updates_every_tick() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let id = sim.add_player();
+        sim.step();
+        sim.take_json(&id);
+        sim.step_n(3);
+        let messages = sim.take_json(&id);
+        let updates = opcodes(&messages)
+            .into_iter()
+            .filter(|opcode| *opcode == "WorldUpdate")
+            .count();
+        assert_eq!(3, updates);
+    }
+
+    #[test]
+    fn world_updates_at_send_rate() {
+        let config = RoomConfig {
+            updates_per_sec: 60,
+            sends_per_sec: 20,
+            ..RoomConfig::default()
+        };
+        let mut sim = Simulation::new(config);
+        let id = sim.add_player();
+        sim.step_n(3);
+        sim.take_json(&id);
+        sim.step_n(6);
+        let messages = sim.take_json(&id);
+        let updates = opcodes(&messages)
+            .into_iter()
+            .filter(|opcode| *opcode == "WorldUpdate")
+            .count();
+        assert_eq!(2, updates);
+    }
+
+    #[test]
+    fn input_moves_paddle() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let left = sim.add_player();
+        sim.add_player();
+        // Paddles may move during the countdown
+        sim.step_n(2);
+        let before = pos(&sim, &left);
+        let tick = sim.curr_tick() + 1;
+        sim.receive(
+            &left,
+            ClientMessage::Input(InputMsg {
+                id: 1,
+                tick,
+                seen_tick: tick - 1,
+                command: Command::MoveUp,
+                active: true,
+            }),
+        );
+        sim.step();
+        assert!(pos(&sim, &left).y < before.y);
+    }
+
+    #[test]
+    fn claimed_lag_is_capped_by_round_trip() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let left = sim.add_player();
+        sim.add_player();
+        sim.step_n(20);
+        let tick = sim.curr_tick() + 1;
+        sim.receive(
+            &left,
+            ClientMessage::Input(InputMsg {
+                id: 1,
+                tick,
+                seen_tick: tick - 15,
+                command: Command::MoveUp,
+                active: true,
+            }),
+        );
+        sim.step();
+        // Nothing answered our pings yet, so there is no round trip to allow for
+        let entity = sim.entity(&left).expect("Player has no entity");
+        assert_eq!(0, sim.world().read::<Paddle>().get(entity).unwrap().lag_ticks);
+    }
+
+    #[test]
+    fn stale_input_costs_strike() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let left = sim.add_player();
+        sim.add_player();
+        sim.step_n(2);
+        let before = pos(&sim, &left);
+        for &(id, active) in &[(2, false), (1, true)] {
+            let tick = sim.curr_tick() + 1;
+            sim.receive(
+                &left,
+                ClientMessage::Input(InputMsg {
+                    id,
+                    tick,
+                    seen_tick: tick - 1,
+                    command: Command::MoveUp,
+                    active,
+                }),
+            );
+            sim.step();
+        }
+        assert_eq!(before, pos(&sim, &left));
+        let entity = sim.entity(&left).expect("Player has no entity");
+        assert_eq!(1, sim.world().read::<Player>().get(entity).unwrap().strikes);
+    }
+
+    #[test]
+    fn too_many_strikes_make_offender() {
+        let config = RoomConfig {
+            max_strikes: 2,
+            ..RoomConfig::default()
+        };
+        let mut sim = Simulation::new(config);
+        let id = sim.add_player();
+        sim.step();
+        sim.receive(&id, ClientMessage::Ack(1000));
+        sim.step();
+        assert!(sim.room.take_offenders().is_empty());
+        sim.receive(&id, ClientMessage::Ack(1000));
+        sim.step();
+        assert_eq!(vec![id], sim.room.take_offenders());
+    }
+
+    #[test]
+    fn ball_waits_for_countdown() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        sim.add_player();
+        sim.add_player();
+        sim.step_n(2);
+        let start = ball_pos(&sim);
+        let countdown_ticks = sim.config().countdown_ticks();
+        sim.step_n(countdown_ticks - 2);
+        assert_eq!(start, ball_pos(&sim));
+        sim.step_n(3);
+        assert_ne!(start, ball_pos(&sim));
+    }
+
+    #[test]
+    fn ball_speed_ignores_tick_rate() {
+        // Travelled distance a second after the countdown
+        let distance = |updates_per_sec| {
+            let config = RoomConfig {
+                updates_per_sec,
+                ..RoomConfig::default()
+            };
+            let mut sim = Simulation::new(config);
+            sim.add_player();
+            sim.add_player();
+            let countdown_ticks = sim.config().countdown_ticks();
+            sim.step_n(countdown_ticks + 2);
+            let start = ball_pos(&sim);
+            sim.step_n(updates_per_sec as u32);
+            (ball_pos(&sim).x - start.x).abs()
+        };
+        assert_eq!(distance(30), distance(60));
+    }
+
+    #[test]
+    fn greeting_contains_session() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let id = sim.add_player();
+        sim.step();
+        let messages = sim.take_json(&id);
+        assert_eq!(sim.sessions[&id].to_string(), messages[0]["payload"]["session"]);
+    }
+
+    #[test]
+    fn dropped_player_keeps_paddle() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let left = sim.add_player();
+        sim.add_player();
+        sim.step_n(2);
+        let before = pos(&sim, &left);
+        sim.drop_connection(&left);
+        sim.step_n(3);
+        assert_eq!(before, pos(&sim, &left));
+        let entity = sim.entity(&left).expect("Player has no entity");
+        assert!(sim.world().read::<Suspended>().get(entity).is_some());
+    }
+
+    #[test]
+    fn resumed_player_is_greeted_again() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let left = sim.add_player();
+        sim.add_player();
+        sim.step_n(2);
+        sim.drop_connection(&left);
+        sim.step();
+        sim.take_json(&left);
+        assert!(sim.resume(&left));
+        sim.step();
+        let messages = sim.take_json(&left);
+        assert_eq!(Some(&"Greeting"), opcodes(&messages).first());
+        assert_eq!(left.to_string(), messages[0]["payload"]["id"]);
+        let entity = sim.entity(&left).expect("Player has no entity");
+        assert!(sim.world().read::<Suspended>().get(entity).is_none());
+    }
+
+    #[test]
+    fn removed_player_cannot_resume() {
+        let mut sim = Simulation::new(RoomConfig::default());
+        let left = sim.add_player();
+        sim.step();
+        sim.drop_connection(&left);
+        sim.remove_player(&left);
+        sim.step();
+        assert!(!sim.resume(&left));
+        sim.step();
+        assert!(sim.entity(&left).is_none());
+        assert!(sim.take_messages(&left).is_empty());
+    }
+
+    #[test]
+    fn lone_player_gets_bot() {
+        let config = RoomConfig {
+            bot_timeout_secs: 1,
+            ..RoomConfig::default()
+        };
+        let timeout_ticks = config.updates_per_sec as u32;
+        let mut sim = Simulation::new(config);
+        sim.add_player();
+        sim.step_n(timeout_ticks + 2);
+        assert_eq!(1, (&sim.world().read::<Bot>()).join().count());
+    }
+
+    #[test]
+    fn replay_ends_in_the_recorded_state() {
+        let config = RoomConfig {
+            seed: Some(7),
+            replay_dir: Some(env::temp_dir()),
+            bot_timeout_secs: 1,
+            ..RoomConfig::default()
+        };
+        let mut sim = Simulation::new(config);
+        let path = env::temp_dir().join(format!("{}.replay", sim.code()));
+        let player = sim.add_player();
+        // Long enough for a bot to join and the ball to go back and forth
+        for i in 0..400 {
+            if i % 20 == 0 {
+                let tick = sim.curr_tick() + 1;
+                let input = InputMsg {
+                    id: i / 20 + 1,
+                    tick,
+                    seen_tick: tick - 3,
+                    command: Command::MoveDown,
+                    active: i % 40 == 0,
+                };
+                sim.receive(&player, ClientMessage::Input(input));
+            }
+            sim.step();
+        }
+        sim.finish();
+
+        let replay = Replay::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(replay.ticks.iter().any(|record| record.checksum.is_some()));
+        assert!(replay.ticks.iter().any(|record| {
+            record.spawned.iter().any(|spawn| spawn.difficulty.is_some())
+        }));
+        assert!(replay.end.is_some());
+        replay.run(|_, _| {}).unwrap();
+    }
+}
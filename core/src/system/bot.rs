@@ -0,0 +1,253 @@
+extern crate specs;
+use self::specs::{Fetch, Join, ReadStorage, WriteStorage, System, Entities};
+
+use model::comp::{Pos, Vel, Actor, ActorKind, Bounciness, Bot, Paddle, Spectator, ToSpawn,
+                  ToDespawn, Bounds};
+use model::game::{Id, BotConfig, MatchState, Phase, Slots, DeltaTime};
+use model::fixed::Fixed;
+use model::network::{Command, InputMsg, JitterBuffer};
+use super::input_handler::InputMap;
+use util::{IdGen, SeqId};
+use std::sync::RwLock;
+
+pub struct Bots;
+impl<'a> System<'a> for Bots {
+    #[allow(type_complexity)]
+    type SystemData = (Entities<'a>,
+     WriteStorage<'a, Actor>,
+     WriteStorage<'a, Bot>,
+     WriteStorage<'a, ToSpawn>,
+     WriteStorage<'a, ToDespawn>,
+     ReadStorage<'a, Spectator>,
+     ReadStorage<'a, Paddle>,
+     ReadStorage<'a, Bounciness>,
+     ReadStorage<'a, Pos>,
+     ReadStorage<'a, Vel>,
+     Fetch<'a, InputMap>,
+     Fetch<'a, SeqId>,
+     Fetch<'a, BotConfig>,
+     Fetch<'a, Bounds<Pos>>,
+     Fetch<'a, DeltaTime>,
+     Fetch<'a, RwLock<MatchState>>,
+     Fetch<'a, RwLock<Slots>>,
+     Fetch<'a, RwLock<IdGen>>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities,
+             mut actor,
+             mut bot,
+             mut to_spawn,
+             mut to_despawn,
+             spectator,
+             paddle,
+             bounciness,
+             pos,
+             vel,
+             inputs,
+             curr_tick,
+             config,
+             pos_bounds,
+             dt,
+             match_state,
+             slots,
+             id_gen) = data;
+
+        let is_human_waiting = (&*entities, &spectator).join().any(|(entity, _)| {
+            to_despawn.get(entity).is_none()
+        });
+        let has_bot = (&bot).join().next().is_some();
+        if is_human_waiting {
+            // Make room for the human, Spawn will promote them once we're gone
+            let mut bots = Vec::new();
+            for (entity, _) in (&*entities, &bot).join() {
+                bots.push(entity);
+            }
+            for entity in bots {
+                to_despawn.insert(entity, ToDespawn {});
+            }
+        } else if !has_bot {
+            let match_state = match_state.read().unwrap();
+            let slots = slots.read().unwrap();
+            let is_waiting = match match_state.phase() {
+                Phase::WaitingForPlayers | Phase::Paused => true,
+                _ => false,
+            };
+            // Nobody to play against if the room is empty
+            if is_waiting && !slots.is_full() && !slots.is_empty() &&
+                match_state.ticks_in_phase() >= config.fill_timeout_ticks
+            {
+                let id = id_gen.write().unwrap().gen();
+                let entity = entities.create();
+                actor.insert(
+                    entity,
+                    Actor {
+                        id,
+                        kind: ActorKind::Bot,
+                    },
+                );
+                bot.insert(entity, Bot::new(config.difficulty.clone(), &id));
+                to_spawn.insert(entity, ToSpawn {});
+            }
+        }
+
+        let ball = (&pos, &vel, &bounciness).join().next();
+        let mut inputs = inputs.write().unwrap();
+        for (entity, actor, mut bot, paddle, own_pos) in
+            (&*entities, &actor, &mut bot, &paddle, &pos).join()
+        {
+            if to_despawn.get(entity).is_some() {
+                continue;
+            }
+            if bot.ticks_until_reaction == 0 {
+                bot.ticks_until_reaction = bot.difficulty.reaction_ticks;
+                let prediction = match ball {
+                    Some((ball_pos, ball_vel, _)) => {
+                        predict_impact(own_pos.x, ball_pos, ball_vel, &pos_bounds)
+                    }
+                    None => None,
+                };
+                bot.target_y = Some(match prediction {
+                    Some(y) => y + Fixed::from(bot.miss()),
+                    // Wait in the middle while the ball is going away
+                    None => (pos_bounds.min.y + pos_bounds.max.y) / 2,
+                });
+            } else {
+                bot.ticks_until_reaction -= 1;
+            }
+
+            let command = decide(bot.target_y, own_pos.y, dt.scale(paddle.speed));
+            if command != bot.pressed {
+                let buffer = inputs.entry(actor.id).or_insert_with(
+                    JitterBuffer::default,
+                );
+                if let Some(pressed) = bot.pressed.take() {
+                    bot.last_input += 1;
+                    buffer.push(InputMsg {
+                        id: bot.last_input,
+                        tick: *curr_tick,
+                        seen_tick: *curr_tick,
+                        command: pressed,
+                        active: false,
+                    });
+                }
+                if let Some(ref command) = command {
+                    bot.last_input += 1;
+                    buffer.push(InputMsg {
+                        id: bot.last_input,
+                        tick: *curr_tick,
+                        seen_tick: *curr_tick,
+                        command: command.clone(),
+                        active: true,
+                    });
+                }
+                bot.pressed = command;
+            }
+        }
+    }
+}
+
+/// Where the ball will cross the given x, taking bounces off the walls into account
+fn predict_impact(x: Fixed, pos: &Pos, vel: &Vel, bounds: &Bounds<Pos>) -> Option<Fixed> {
+    let distance = x - pos.x;
+    if vel.x == Fixed::zero() || distance.signum() != vel.x.signum() {
+        return None;
+    }
+    // Slow balls travel further than a Fixed can hold until they get here, so work on the
+    // raw bits with some room to spare
+    let travel = i64::from(vel.y.to_bits()) * i64::from(distance.to_bits()) /
+        i64::from(vel.x.to_bits());
+    let height = i64::from((bounds.max.y - bounds.min.y).to_bits());
+    let period = 2 * height;
+    let mut y = (i64::from((pos.y - bounds.min.y).to_bits()) + travel) % period;
+    if y < 0 {
+        y += period;
+    }
+    if y > height {
+        y = period - y;
+    }
+    Some(bounds.min.y + Fixed::from_bits(y as i32))
+}
+
+fn decide(target_y: Option<Fixed>, y: Fixed, step: Fixed) -> Option<Command> {
+    let target_y = match target_y {
+        Some(target_y) => target_y,
+        None => return None,
+    };
+    // Don't jitter around the target, a single tick would overshoot it
+    let tolerance = step / 2;
+    if target_y < y - tolerance {
+        Some(Command::MoveUp)
+    } else if target_y > y + tolerance {
+        Some(Command::MoveDown)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use model::comp::Difficulty;
+    use model::game::Vector;
+
+    fn arena() -> Bounds<Pos> {
+        Bounds {
+            min: Pos::from(Vector::new(0, 0)),
+            max: Pos::from(Vector::new(1000, 1000)),
+        }
+    }
+
+    fn predict(x: i32, pos: (i32, i32), vel: (i32, i32)) -> Option<Fixed> {
+        let pos = Pos::from(Vector::new(pos.0, pos.1));
+        let vel = Vel::from(Vector::new(vel.0, vel.1));
+        predict_impact(Fixed::from(x), &pos, &vel, &arena())
+    }
+
+    #[test]
+    fn predicts_straight_paths() {
+        assert_eq!(Some(Fixed::from(500)), predict(20, (500, 500), (-100, 0)));
+        assert_eq!(Some(Fixed::from(100)), predict(100, (500, 500), (-100, -100)));
+    }
+
+    #[test]
+    fn predicts_bounces_off_walls() {
+        // Up to the top wall and 300 back down
+        assert_eq!(Some(Fixed::from(300)), predict(100, (500, 500), (-100, -200)));
+        // Off the bottom, all the way up to the top and back down again
+        assert_eq!(Some(Fixed::from(700)), predict(900, (100, 900), (100, 300)));
+    }
+
+    #[test]
+    fn ignores_balls_flying_away() {
+        assert_eq!(None, predict(20, (500, 500), (100, 0)));
+        assert_eq!(None, predict(20, (500, 500), (0, 100)));
+    }
+
+    #[test]
+    fn moves_towards_target() {
+        let step = Fixed::from(15);
+        let target = Some(Fixed::from(500));
+        assert_eq!(None, decide(None, Fixed::from(500), step));
+        assert_eq!(Some(Command::MoveUp), decide(target, Fixed::from(510), step));
+        assert_eq!(Some(Command::MoveDown), decide(target, Fixed::from(490), step));
+        // Less than half a step away
+        assert_eq!(None, decide(target, Fixed::from(505), step));
+        // Faster bots settle for being closer to the target
+        assert_eq!(None, decide(target, Fixed::from(510), Fixed::from(30)));
+    }
+
+    #[test]
+    fn misses_stay_within_prediction_error() {
+        for &prediction_error in &[0, 5, 40] {
+            let difficulty = Difficulty {
+                prediction_error,
+                ..Difficulty::default()
+            };
+            let mut bot = Bot::new(difficulty, &Id::nil());
+            let misses: Vec<i32> = (0..1000).map(|_| bot.miss()).collect();
+            assert!(misses.iter().all(|miss| miss.abs() <= prediction_error));
+            // Perfect bots never miss, everyone else does now and then
+            assert_eq!(prediction_error > 0, misses.iter().any(|&miss| miss != 0));
+        }
+    }
+}
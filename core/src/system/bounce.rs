@@ -1,69 +1,242 @@
 extern crate specs;
 
-use self::specs::{Fetch, Join, WriteStorage, ReadStorage, System};
+use self::specs::{Fetch, Join, WriteStorage, ReadStorage, System, Entities};
 
-use model::comp::{Pos, Vel, Bounciness, Bounds, Actor};
-use model::game::{Id, Vector};
+use model::comp::{Pos, Vel, Bounciness, Bounds, Actor, Collision, Paddle};
+use model::game::{Id, Vector, Side, Score, Goal, MatchState, Phase, DeltaTime};
+use model::fixed::Fixed;
 use collision::World;
+use config::{GameConfig, MAX_UNITS};
 use util::angle;
 use std::sync::RwLock;
+use std::collections::HashMap;
 
 pub struct Bounce;
 impl<'a> System<'a> for Bounce {
     #[allow(type_complexity)]
-    type SystemData = (WriteStorage<'a, Pos>,
+    type SystemData = (Entities<'a>,
+     WriteStorage<'a, Pos>,
      WriteStorage<'a, Vel>,
      ReadStorage<'a, Actor>,
      ReadStorage<'a, Bounciness>,
+     ReadStorage<'a, Paddle>,
+     WriteStorage<'a, Collision>,
      Fetch<'a, Bounds<Pos>>,
-     Fetch<'a, RwLock<World<Id>>>);
+     Fetch<'a, RwLock<World<Id>>>,
+     Fetch<'a, RwLock<Score>>,
+     Fetch<'a, RwLock<Vec<Goal>>>,
+     Fetch<'a, RwLock<MatchState>>,
+     Fetch<'a, DeltaTime>,
+     Fetch<'a, GameConfig>);
 
-    fn run(&mut self, (mut pos, mut vel, actor, bounciness, pos_bounds, world): Self::SystemData) {
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities,
+             mut pos,
+             mut vel,
+             actor,
+             bounciness,
+             paddle,
+             mut collision,
+             pos_bounds,
+             world,
+             score,
+             goals,
+             match_state,
+             dt,
+             config) = data;
+        let mut match_state = match_state.write().unwrap();
+        if !match_state.is_playing() {
+            return;
+        }
         let world = world.read().unwrap();
-        for (mut pos, mut vel, actor, _) in (&mut pos, &mut vel, &actor, &bounciness).join() {
-            handle_movement(actor, &mut pos, &mut vel, &pos_bounds, &world);
+        let mut score = score.write().unwrap();
+        let mut goals = goals.write().unwrap();
+        // Moving paddles put spin on the ball
+        let spins: HashMap<Id, Fixed> = (&actor, &vel, &paddle)
+            .join()
+            .map(|(actor, vel, _)| (actor.id, vel.y))
+            .collect();
+        for (entity, mut pos, mut vel, actor, _) in
+            (&*entities, &mut pos, &mut vel, &actor, &bounciness).join()
+        {
+            match collision.remove(entity) {
+                Some(collision) => {
+                    handle_collision(&collision, &pos, &mut vel, &spins, &dt, &config)
+                }
+                None => handle_overlap(actor, &pos, &mut vel, &world, &spins, &dt, &config),
+            }
+            if let Some(goal) = handle_movement(&mut pos, &mut vel, &pos_bounds, &dt, &config) {
+                score.credit(goal.scorer);
+                if score.get(goal.scorer) >= match_state.score_limit {
+                    match_state.finish(goal.scorer);
+                } else {
+                    match_state.set_phase(Phase::Countdown);
+                }
+                goals.push(goal);
+            }
         }
     }
 }
 
-fn handle_movement(
+fn handle_collision(
+    collision: &Collision,
+    pos: &Pos,
+    vel: &mut Vel,
+    spins: &HashMap<Id, Fixed>,
+    dt: &DeltaTime,
+    config: &GameConfig,
+) {
+    let normal = &collision.normal;
+    if normal.x != Fixed::zero() {
+        let spin = spins.get(&collision.other).cloned().unwrap_or_default();
+        reflect(pos, vel, collision.other_pos.y, normal.x.signum(), spin, dt, config);
+    } else {
+        vel.y = normal.y * vel.y.abs();
+    }
+}
+
+/// Something moved into us without us running into it
+fn handle_overlap(
     actor: &Actor,
-    pos: &mut Pos,
+    pos: &Pos,
     vel: &mut Vel,
-    bounds: &Bounds<Pos>,
     world: &World<Id>,
+    spins: &HashMap<Id, Fixed>,
+    dt: &DeltaTime,
+    config: &GameConfig,
 ) {
-    world.query_intersects_id(&actor.id, |other| {
-        let own: Vector = Vector { x: pos.x, y: pos.y };
-        let other = Vector {
-            x: other.bounds.x,
-            y: other.bounds.y,
-        };
-        if own == other {
-            vel.x = -vel.x;
-        } else {
-            let angle = angle(&own, &other);
-            const SPEED_UP: i32 = 1;
-            let sped_up_x = vel.x.abs() + SPEED_UP;
-            let sped_up_y = vel.y + vel.y / vel.y.abs() * SPEED_UP;
-            if angle > 270.0 || angle < 90.0 {
-                vel.x = -sped_up_x;
-                vel.y = sped_up_y;
-            } else {
-                vel.x = sped_up_x;
-                vel.y = sped_up_y;
+    let result = world.query_intersects_id(&actor.id, |other| {
+        // Bounds are in whole units, so round our position before comparing
+        let own = Vector::new(pos.x.round(), pos.y.round());
+        let other_pos = Vector::new(other.bounds.x, other.bounds.y);
+        match angle(&own, &other_pos) {
+            // Right on top of each other, there is no telling where it came from
+            None => vel.x = -vel.x,
+            Some(angle) => {
+                let right = angle > Fixed::from(270) || angle < Fixed::from(90);
+                let direction = if right { -1 } else { 1 };
+                let spin = spins.get(other.id).cloned().unwrap_or_default();
+                reflect(pos, vel, other_pos.y, direction, spin, dt, config);
             }
         }
     });
-    let next_x = pos.x + vel.x;
-    let next_y = pos.y + vel.y;
-    if next_x > bounds.max.x || next_x < bounds.min.x {
-        pos.x = 500;
-        pos.y = 500;
-        vel.x = 7;
-        vel.y = 11;
+    if let Err(err) = result {
+        println!("Failed to bounce actor {}: {}", actor.id, err);
+    }
+}
+
+/// Sends the ball back like classic Pong does: the further from the center of the paddle
+/// it hits, the steeper it leaves
+#[allow(too_many_arguments)]
+fn reflect(
+    pos: &Pos,
+    vel: &mut Vel,
+    paddle_y: Fixed,
+    direction: i32,
+    spin: Fixed,
+    dt: &DeltaTime,
+    config: &GameConfig,
+) {
+    let ball = &config.ball;
+    // Where along the paddle we hit, from -1 at the top edge to 1 at the bottom edge
+    let reach = Fixed::from(config.paddle.height + ball.size) / 2;
+    let offset = ((pos.y - paddle_y) / reach).max(Fixed::from(-1)).min(Fixed::from(1));
+    let angle = offset * ball.max_bounce_angle;
+    // Speeding up forever would overflow eventually
+    let speed = (vel.x.hypot(vel.y) + ball.speed_up).min(Fixed::from(MAX_UNITS));
+    // Without at least a unit per tick the ball would never reach the other side
+    let min_speed_x = Fixed::from(dt.ticks_per_sec as i32);
+    vel.x = (speed * angle.cos_deg()).max(min_speed_x) * direction;
+    vel.y = speed * angle.sin_deg() + spin * ball.spin;
+}
+
+fn handle_movement(
+    pos: &mut Pos,
+    vel: &mut Vel,
+    bounds: &Bounds<Pos>,
+    dt: &DeltaTime,
+    config: &GameConfig,
+) -> Option<Goal> {
+    let next_x = pos.x + dt.scale(vel.x);
+    let next_y = pos.y + dt.scale(vel.y);
+    let conceded = if next_x < bounds.min.x {
+        Some(Side::Left)
+    } else if next_x > bounds.max.x {
+        Some(Side::Right)
+    } else {
+        None
+    };
+    if let Some(conceded) = conceded {
+        serve(pos, vel, conceded, config);
+        return Some(Goal { scorer: conceded.opposite() });
     }
     if next_y > bounds.max.y || next_y < bounds.min.y {
         vel.y = -vel.y;
     }
+    None
+}
+
+/// Puts the ball back in the middle, flying towards the given side
+fn serve(pos: &mut Pos, vel: &mut Vel, towards: Side, config: &GameConfig) {
+    let center = config.arena.center();
+    let serve = &config.ball.serve;
+    pos.x = center.x;
+    pos.y = center.y;
+    vel.x = match towards {
+        Side::Left => -serve.x.abs(),
+        Side::Right => serve.x.abs(),
+    };
+    vel.y = serve.y;
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bounce_off(y: i32, spin: i32) -> Vel {
+        let config = GameConfig::default();
+        let pos = Pos::from(Vector::new(500, y));
+        let mut vel = Vel::from(Vector::new(-300, 0));
+        let dt = DeltaTime { ticks_per_sec: 30 };
+        reflect(&pos, &mut vel, Fixed::from(500), 1, Fixed::from(spin), &dt, &config);
+        vel
+    }
+
+    fn degrees(vel: &Vel) -> f64 {
+        vel.y.to_f64().atan2(vel.x.to_f64()).to_degrees()
+    }
+
+    #[test]
+    fn center_hit_goes_straight_back() {
+        assert_eq!(Vel::from(Vector::new(330, 0)), bounce_off(500, 0));
+    }
+
+    #[test]
+    fn edge_hit_goes_steep() {
+        // Half the paddle and half the ball away from the center
+        let vel = bounce_off(545, 0);
+        assert!((degrees(&vel) - 60.0).abs() < 5.0);
+        let vel = bounce_off(455, 0);
+        assert!((degrees(&vel) + 60.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn angle_grows_with_offset() {
+        let near = bounce_off(510, 0);
+        let far = bounce_off(530, 0);
+        assert!(degrees(&near) > 0.0);
+        assert!(degrees(&far) > degrees(&near));
+    }
+
+    #[test]
+    fn miss_by_far_is_clamped() {
+        assert_eq!(bounce_off(545, 0), bounce_off(2000, 0));
+    }
+
+    #[test]
+    fn moving_paddle_adds_spin() {
+        assert_eq!(187.5, bounce_off(500, 750).y.to_f64());
+        assert_eq!(-187.5, bounce_off(500, -750).y.to_f64());
+    }
 }
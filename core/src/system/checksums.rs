@@ -0,0 +1,53 @@
+extern crate specs;
+use self::specs::{Fetch, Join, ReadStorage, System};
+
+use model::comp::{Actor, Pos, Vel};
+use model::game::{Id, Vector, Checksum};
+use util::{fnv1a, SeqId, FNV_OFFSET};
+use std::sync::RwLock;
+
+/// Only runs in deterministic mode, where equal inputs have to lead to equal checksums
+pub struct Checksums;
+impl<'a> System<'a> for Checksums {
+    #[allow(type_complexity)]
+    type SystemData = (ReadStorage<'a, Actor>,
+     ReadStorage<'a, Pos>,
+     ReadStorage<'a, Vel>,
+     Fetch<'a, SeqId>,
+     Fetch<'a, RwLock<Option<Checksum>>>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (actor, pos, vel, curr_tick, checksum) = data;
+        let value = hash_actors((&actor, &pos, &vel).join().map(|(actor, pos, vel)| {
+            (&actor.id, &**pos, &**vel)
+        }));
+        *checksum.write().unwrap() = Some(Checksum {
+            tick: *curr_tick,
+            value,
+        });
+    }
+}
+
+/// Hashes the position and velocity of every actor, no matter in which order they come
+pub fn hash_actors<'a, I>(actors: I) -> u32
+where
+    I: Iterator<Item = (&'a Id, &'a Vector, &'a Vector)>,
+{
+    let mut actors: Vec<_> = actors.collect();
+    actors.sort_by_key(|&(id, _, _)| *id);
+    actors.iter().fold(FNV_OFFSET, |hash, &(id, pos, vel)| {
+        let hash = fnv1a(hash, id.as_bytes());
+        [pos.x, pos.y, vel.x, vel.y].iter().fold(hash, |hash, num| {
+            fnv1a(hash, &to_bytes(num.to_bits()))
+        })
+    })
+}
+
+fn to_bytes(num: i32) -> [u8; 4] {
+    [
+        (num >> 24) as u8,
+        (num >> 16) as u8,
+        (num >> 8) as u8,
+        num as u8,
+    ]
+}
@@ -2,8 +2,9 @@ extern crate specs;
 use self::specs::{Join, ReadStorage, System, Entities, Fetch};
 
 use model::comp::{ToDespawn, Actor};
-use model::game::Id;
+use model::game::{Id, Slots};
 use collision::World;
+use replay::SharedRecorder;
 use std::sync::RwLock;
 
 pub struct Despawn;
@@ -12,13 +13,19 @@ impl<'a> System<'a> for Despawn {
     type SystemData = (Entities<'a>,
      ReadStorage<'a, Actor>,
      ReadStorage<'a, ToDespawn>,
-     Fetch<'a, RwLock<World<Id>>>);
+     Fetch<'a, RwLock<World<Id>>>,
+     Fetch<'a, RwLock<Slots>>,
+     Fetch<'a, SharedRecorder>);
 
-    fn run(&mut self, (entities, actor, despawn, world): Self::SystemData) {
+    fn run(&mut self, (entities, actor, despawn, world, slots, recorder): Self::SystemData) {
         let mut world = world.write().unwrap();
+        let mut slots = slots.write().unwrap();
+        let mut recorder = recorder.write().unwrap();
         for (entity, actor, _) in (&*entities, &actor, &despawn).join() {
             entities.delete(entity);
             world.remove(&actor.id);
+            slots.release(&actor.id);
+            recorder.despawned(&actor.id);
         }
     }
 }
@@ -1,42 +1,112 @@
 extern crate specs;
-use self::specs::{Fetch, Join, WriteStorage, ReadStorage, System};
+use self::specs::{Fetch, Join, WriteStorage, ReadStorage, System, Entities};
 
-use model::comp::{Vel, Player, Actor};
-use model::game::Id;
-use model::network::{Command, ClientMsg};
+use model::comp::{Vel, Player, Actor, Spectator, Paddle, Suspended};
+use model::game::{Id, DeltaTime};
+use model::network::{Command, InputMsg, JitterBuffer, Offense};
+use model::fixed::Fixed;
+use util::SeqId;
+use replay::SharedRecorder;
 
 use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 
-type InputMap = Arc<RwLock<HashMap<Id, Vec<ClientMsg>>>>;
+pub type InputMap = Arc<RwLock<HashMap<Id, JitterBuffer>>>;
 
 pub struct InputHandler;
 impl<'a> System<'a> for InputHandler {
-    type SystemData = (Fetch<'a, InputMap>,
+    #[allow(type_complexity)]
+    type SystemData = (Entities<'a>,
+     Fetch<'a, InputMap>,
+     Fetch<'a, SeqId>,
      WriteStorage<'a, Vel>,
      WriteStorage<'a, Player>,
-     ReadStorage<'a, Actor>);
+     ReadStorage<'a, Actor>,
+     WriteStorage<'a, Paddle>,
+     ReadStorage<'a, Spectator>,
+     ReadStorage<'a, Suspended>,
+     Fetch<'a, DeltaTime>,
+     Fetch<'a, SharedRecorder>);
 
-    fn run(&mut self, (inputs, mut acc, mut player, actor): Self::SystemData) {
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities,
+             inputs,
+             curr_tick,
+             mut vel,
+             mut player,
+             actor,
+             mut paddle,
+             spectator,
+             suspended,
+             dt,
+             recorder) = data;
         let mut inputs = inputs.write().unwrap();
-        for (mut player, mut vel, actor) in (&mut player, &mut acc, &actor).join() {
-            if let Some(mut key_states) = inputs.get_mut(&actor.id) {
-                for key_state in key_states.drain(..) {
-                    update_player_inputs(&mut player, &key_state);
-                    handle_key_state(player, &mut vel, &key_state);
+        let mut recorder = recorder.write().unwrap();
+        // Spectators don't control anything
+        for (actor, _) in (&actor, &spectator).join() {
+            inputs.remove(&actor.id);
+        }
+        // Nobody is pressing any keys while their connection is gone
+        for (mut vel, _) in (&mut vel, &suspended).join() {
+            vel.y = Fixed::zero();
+        }
+        // Bots don't have a player, but feed their inputs through here as well
+        for (entity, mut vel, actor, mut paddle) in
+            (&*entities, &mut vel, &actor, &mut paddle).join()
+        {
+            if let Some(mut buffer) = inputs.get_mut(&actor.id) {
+                let rejected = buffer.reject_invalid(*curr_tick);
+                if rejected > 0 {
+                    println!(
+                        "Client {}: Rejected {} inputs not meant for around tick {}",
+                        actor.id,
+                        rejected,
+                        *curr_tick
+                    );
                 }
-                let bufferlen = 10;
-                let len = player.inputs.len();
-                if len > bufferlen {
-                    player.inputs.drain(0..len - bufferlen);
+                let mut player = player.get_mut(entity);
+                for mut key_state in buffer.take_due(*curr_tick) {
+                    // Ids only ever go up, anything else would let clients spoof `last_input`
+                    if let Some(ref mut player) = player {
+                        if key_state.id <= player.last_input {
+                            player.strikes += 1;
+                            println!(
+                                "Client {}: Strike {} for {}",
+                                actor.id,
+                                player.strikes,
+                                Offense::StaleInput
+                            );
+                            continue;
+                        }
+                    }
+                    // Clients could claim to have seen any tick, but nothing older than their
+                    // round trip. Replays get the clamped tick, they have no round trip.
+                    if let Some(ref player) = player {
+                        let max_lag = player.latency.rtt().map_or(0, |rtt| dt.ticks_in(rtt));
+                        let earliest = curr_tick.saturating_sub(max_lag);
+                        key_state.seen_tick = key_state.seen_tick.max(earliest);
+                    }
+                    recorder.applied(&actor.id, &key_state);
+                    // Hits get checked against what the player was looking at
+                    paddle.lag_ticks = curr_tick.saturating_sub(key_state.seen_tick) as usize;
+                    if let Some(ref mut player) = player {
+                        update_player_inputs(player, &key_state);
+                    }
+                    handle_key_state(paddle, &mut vel, &key_state);
+                }
+                if let Some(player) = player {
+                    let bufferlen = 10;
+                    let len = player.inputs.len();
+                    if len > bufferlen {
+                        player.inputs.drain(0..len - bufferlen);
+                    }
                 }
             }
         }
-
     }
 }
 
-fn update_player_inputs(player: &mut Player, key_state: &ClientMsg) {
+fn update_player_inputs(player: &mut Player, key_state: &InputMsg) {
     let mut input = HashMap::new();
     if let Some(last_input) = player.inputs.last() {
         input.clone_from(last_input);
@@ -46,20 +116,20 @@ fn update_player_inputs(player: &mut Player, key_state: &ClientMsg) {
     player.last_input = key_state.id;
 }
 
-fn handle_key_state(_: &Player, vel: &mut Vel, key_state: &ClientMsg) {
+fn handle_key_state(paddle: &Paddle, vel: &mut Vel, key_state: &InputMsg) {
     match key_state.command {
         Command::MoveUp => {
             if key_state.active {
-                vel.y = -25
-            } else if vel.y < 0 {
-                vel.y = 0
+                vel.y = -paddle.speed
+            } else if vel.y < Fixed::zero() {
+                vel.y = Fixed::zero()
             }
         }
         Command::MoveDown => {
             if key_state.active {
-                vel.y = 25
-            } else if vel.y > 0 {
-                vel.y = 0
+                vel.y = paddle.speed
+            } else if vel.y > Fixed::zero() {
+                vel.y = Fixed::zero()
             }
         }
     }
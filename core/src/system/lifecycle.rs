@@ -0,0 +1,56 @@
+extern crate specs;
+use self::specs::{Join, ReadStorage, System, Fetch};
+
+use model::comp::{Paddle, Suspended};
+use model::game::{MatchState, Phase, Score, Slots};
+use std::sync::RwLock;
+
+pub struct Lifecycle;
+impl<'a> System<'a> for Lifecycle {
+    #[allow(type_complexity)]
+    type SystemData = (ReadStorage<'a, Paddle>,
+     ReadStorage<'a, Suspended>,
+     Fetch<'a, RwLock<Slots>>,
+     Fetch<'a, RwLock<MatchState>>,
+     Fetch<'a, RwLock<Score>>);
+
+    fn run(&mut self, (paddle, suspended, slots, match_state, score): Self::SystemData) {
+        // A player whose connection dropped doesn't count until it comes back
+        let is_full = slots.read().unwrap().is_full() &&
+            (&paddle, &suspended).join().next().is_none();
+
+        let mut match_state = match_state.write().unwrap();
+        match_state.tick();
+        match match_state.phase() {
+            Phase::WaitingForPlayers | Phase::Paused => {
+                if is_full {
+                    match_state.set_phase(Phase::Countdown);
+                }
+            }
+            Phase::Countdown => {
+                if !is_full {
+                    let has_started = *score.read().unwrap() != Score::default();
+                    let phase = if has_started {
+                        Phase::Paused
+                    } else {
+                        Phase::WaitingForPlayers
+                    };
+                    match_state.set_phase(phase);
+                } else if match_state.ticks_left() == 0 {
+                    match_state.set_phase(Phase::Playing);
+                }
+            }
+            Phase::Playing => {
+                if !is_full {
+                    match_state.set_phase(Phase::Paused);
+                }
+            }
+            Phase::Finished => {
+                if match_state.ticks_left() == 0 {
+                    *score.write().unwrap() = Score::default();
+                    match_state.set_phase(Phase::WaitingForPlayers);
+                }
+            }
+        }
+    }
+}
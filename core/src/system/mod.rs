@@ -1,9 +1,13 @@
 pub use self::physics::Physics;
-pub use self::sending::Sending;
-pub use self::input_handler::InputHandler;
+pub use self::sending::{Sending, DroppedClients, PingQueue, ChatQueue, Latencies};
+pub use self::input_handler::{InputHandler, InputMap};
 pub use self::bounce::Bounce;
 pub use self::spawn::Spawn;
 pub use self::despawn::Despawn;
+pub use self::lifecycle::Lifecycle;
+pub use self::bot::Bots;
+pub use self::checksums::{Checksums, hash_actors};
+pub use self::recording::Recording;
 
 mod physics;
 mod sending;
@@ -11,3 +15,7 @@ mod input_handler;
 mod bounce;
 mod spawn;
 mod despawn;
+mod lifecycle;
+mod bot;
+mod checksums;
+mod recording;
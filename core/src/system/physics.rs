@@ -2,12 +2,18 @@ extern crate specs;
 
 use self::specs::{Join, WriteStorage, ReadStorage, System, Fetch, Entities};
 
-use model::comp::{Pos, Vel, Acc, Bounds, Friction, Actor, ToDespawn};
-use model::game::Id;
+use model::comp::{Pos, Vel, Acc, Bounds, Friction, Actor, ToDespawn, Bounciness, Collision,
+                  Paddle};
+use model::game::{Id, Vector, MatchState, LagCompensation, DeltaTime};
+use model::fixed::Fixed;
 use util::clamp;
-use collision::World;
+use collision::{World, History};
 use std::sync::RwLock;
 
+/// What a swept actor ran into first: its id, the fraction of the movement until then,
+/// the normal of the hit surface and where the other actor was
+type Hit = (Id, Fixed, Vector, Vector);
+
 pub struct Physics;
 impl<'a> System<'a> for Physics {
     #[allow(type_complexity)]
@@ -16,11 +22,18 @@ impl<'a> System<'a> for Physics {
      ReadStorage<'a, Acc>,
      ReadStorage<'a, Friction>,
      ReadStorage<'a, Actor>,
-     ReadStorage<'a, ToDespawn>,
+     WriteStorage<'a, ToDespawn>,
+     ReadStorage<'a, Bounciness>,
+     ReadStorage<'a, Paddle>,
+     WriteStorage<'a, Collision>,
      Entities<'a>,
      Fetch<'a, Bounds<Vel>>,
      Fetch<'a, Bounds<Pos>>,
-     Fetch<'a, RwLock<World<Id>>>);
+     Fetch<'a, RwLock<World<Id>>>,
+     Fetch<'a, RwLock<History<Id>>>,
+     Fetch<'a, LagCompensation>,
+     Fetch<'a, DeltaTime>,
+     Fetch<'a, RwLock<MatchState>>);
 
     fn run(&mut self, data: Self::SystemData) {
         let (mut pos,
@@ -28,39 +41,150 @@ impl<'a> System<'a> for Physics {
              acc,
              friction,
              actor,
-             to_despawn,
+             mut to_despawn,
+             bounciness,
+             paddle,
+             mut collision,
              entities,
              vel_bounds,
              pos_bounds,
-             world) = data;
+             world,
+             history,
+             lag_compensation,
+             dt,
+             match_state) = data;
+        let match_state = match_state.read().unwrap();
+        if !match_state.moves_paddles() {
+            return;
+        }
         for (mut vel, acc) in (&mut vel, &acc).join() {
+            let acc = dt.scale_vector(acc);
             vel.x = clamp(vel.x + acc.x, vel_bounds.min.x, vel_bounds.max.x);
             vel.y = clamp(vel.y + acc.y, vel_bounds.min.y, vel_bounds.max.y);
         }
 
         for (mut vel, friction) in (&mut vel, &friction).join() {
-            if vel.y == 0 {
+            if vel.y == Fixed::zero() {
                 continue;
             }
-            let sign = if vel.y < 0 { 1 } else { -1 };
+            let sign = if vel.y < Fixed::zero() { 1 } else { -1 };
             use std::ops::Deref;
-            let friction = *friction.deref();
-            let new_vel = sign * friction;
+            let friction = dt.scale(*friction.deref());
+            let new_vel = friction * sign;
             vel.y += if new_vel > -friction && new_vel < friction {
-                0
+                Fixed::zero()
             } else {
                 new_vel
             }
         }
 
+        // Remote players saw the paddles where they were a few ticks ago
+        let rewinds: Vec<(Id, usize)> = (&actor, &paddle)
+            .join()
+            .filter(|&(_, paddle)| paddle.lag_ticks > 0)
+            .map(|(actor, paddle)| {
+                (actor.id, paddle.lag_ticks.min(lag_compensation.max_rewind_ticks))
+            })
+            .collect();
+
         let mut world = world.write().unwrap();
+        let mut history = history.write().unwrap();
+        let mut faulty = Vec::new();
         for (mut pos, vel, actor, entity) in (&mut pos, &vel, &actor, &*entities).join() {
             if to_despawn.get(entity).is_some() {
                 continue;
             }
-            pos.x = clamp(pos.x + vel.x, pos_bounds.min.x, pos_bounds.max.x);
-            pos.y = clamp(pos.y + vel.y, pos_bounds.min.y, pos_bounds.max.y);
-            world.place(&actor.id, pos);
+            let is_bouncy = bounciness.get(entity).is_some();
+            let mut delta = dt.scale_vector(vel);
+            if is_bouncy {
+                if !match_state.is_playing() {
+                    delta = Vector::new(0, 0);
+                }
+                // Other systems might have teleported us since we last moved
+                if let Err(err) = world.try_place(&actor.id, pos) {
+                    faulty.push((entity, actor.id, err));
+                    continue;
+                }
+                // Only bouncy objects are swept, everything else can push into them
+                let hit = match world.query_sweep_id(&actor.id, &delta) {
+                    Ok(hit) => hit.map(|sweep| {
+                        let other_pos = Vector::new(sweep.bounds.x, sweep.bounds.y);
+                        (*sweep.id, sweep.time, sweep.normal, other_pos)
+                    }),
+                    Err(err) => {
+                        faulty.push((entity, actor.id, err));
+                        continue;
+                    }
+                };
+                let hit = rewind_hit(&world, &history, &rewinds, &actor.id, &delta, hit);
+                if let Some((other, time, normal, other_pos)) = hit {
+                    // The sweep started from our bounds in whole units, stopping on a whole unit
+                    // as well keeps them right in front of the obstacle. Truncating does the rest.
+                    if let Some(bounds) = world.get(&actor.id) {
+                        let x = Fixed::from(bounds.x + (delta.x * time).trunc());
+                        let y = Fixed::from(bounds.y + (delta.y * time).trunc());
+                        delta = Vector {
+                            x: x - pos.x,
+                            y: y - pos.y,
+                        };
+                    }
+                    collision.insert(
+                        entity,
+                        Collision {
+                            other,
+                            normal,
+                            other_pos,
+                        },
+                    );
+                }
+            }
+            pos.x = clamp(pos.x + delta.x, pos_bounds.min.x, pos_bounds.max.x);
+            pos.y = clamp(pos.y + delta.y, pos_bounds.min.y, pos_bounds.max.y);
+            if let Err(err) = world.try_place(&actor.id, pos) {
+                faulty.push((entity, actor.id, err));
+            }
+        }
+        // Only paddles ever get rewound
+        let paddles: Vec<Id> = (&actor, &paddle).join().map(|(actor, _)| actor.id).collect();
+        history.push(&world, &paddles);
+        // Better to lose a single actor than the whole room
+        for (entity, id, err) in faulty {
+            println!("Failed to move actor {}, despawning it: {}", id, err);
+            to_despawn.insert(entity, ToDespawn {});
+        }
+    }
+}
+
+/// Also lets the ball hit paddles where their players saw them, whichever comes first
+fn rewind_hit(
+    world: &World<Id>,
+    history: &History<Id>,
+    rewinds: &[(Id, usize)],
+    id: &Id,
+    delta: &Vector,
+    mut hit: Option<Hit>,
+) -> Option<Hit> {
+    let (bounds, shape, layers) = match (world.get(id), world.get_shape(id), world.get_layers(id)) {
+        (Some(bounds), Some(shape), Some(layers)) => (bounds, shape, layers),
+        _ => return hit,
+    };
+    for &(ref paddle, ticks) in rewinds {
+        // The latest entry is the state at the start of this tick
+        let past = match ticks.checked_sub(1).and_then(|ticks| history.rewind(ticks, paddle)) {
+            Some(past) if layers.collides(&past.layers) => past,
+            _ => continue,
+        };
+        let sweep = bounds.sweep_shape(shape, delta, &past.bounds, past.shape);
+        if let Some((time, normal)) = sweep {
+            let is_earlier = match hit {
+                Some((_, earliest, _, _)) => time < earliest,
+                None => true,
+            };
+            if is_earlier {
+                let past_pos = Vector::new(past.bounds.x, past.bounds.y);
+                hit = Some((*paddle, time, normal, past_pos));
+            }
         }
     }
+    hit
 }
@@ -0,0 +1,45 @@
+extern crate specs;
+use self::specs::{Entities, Fetch, Join, ReadStorage, System};
+
+use model::comp::{Actor, Bot, ToDespawn, Suspended};
+use model::game::Checksum;
+use replay::SharedRecorder;
+use util::SeqId;
+use std::sync::RwLock;
+use std::collections::HashSet;
+
+/// Notes spawns, despawn marks and suspensions right before inputs get applied.
+/// Inputs and despawns are reported by the systems handling them.
+pub struct Recording;
+impl<'a> System<'a> for Recording {
+    #[allow(type_complexity)]
+    type SystemData = (Entities<'a>,
+     ReadStorage<'a, Actor>,
+     ReadStorage<'a, Bot>,
+     ReadStorage<'a, ToDespawn>,
+     ReadStorage<'a, Suspended>,
+     Fetch<'a, SeqId>,
+     Fetch<'a, RwLock<Option<Checksum>>>,
+     Fetch<'a, SharedRecorder>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, actor, bot, to_despawn, suspended, curr_tick, checksum, recorder) = data;
+        let mut recorder = recorder.write().unwrap();
+        if !recorder.is_enabled() {
+            return;
+        }
+        recorder.begin_tick(*curr_tick, *checksum.read().unwrap());
+        let mut present = HashSet::new();
+        for (entity, actor) in (&*entities, &actor).join() {
+            let difficulty = bot.get(entity).map(|bot| &bot.difficulty);
+            recorder.observe(
+                actor,
+                difficulty,
+                to_despawn.get(entity).is_some(),
+                suspended.get(entity).is_some(),
+            );
+            present.insert(actor.id);
+        }
+        recorder.forget_missing(&present);
+    }
+}
@@ -1,136 +1,458 @@
 extern crate specs;
-extern crate futures;
-extern crate serde;
 extern crate serde_json;
 extern crate websocket_server;
 
-use self::specs::{Join, ReadStorage, WriteStorage, System, Entities, EntitiesRes, Fetch};
-use self::futures::{Future, Sink};
+use self::specs::{Join, ReadStorage, WriteStorage, System, Entities, EntitiesRes, Entity, Fetch};
 use self::websocket_server::Message;
-use self::serde::ser::Serialize;
 
-use model::comp::{Pos, Vel, ToSpawn, ToDespawn, Player as PlayerComp, Actor};
-use model::network::{ServerMsg as ClientMessage, OpCode};
-use util::SeqId;
+use model::comp::{Pos, Vel, ToSpawn, ToDespawn, Player as PlayerComp, Actor, Spectator,
+                  Promoted, Resumed};
+use model::network::{ServerMsg, ServerMessage, Format, ActorState, Snapshot, SnapshotHistory,
+                     WorldUpdate, Greeting, ScoreUpdate, MatchStateUpdate, Ping, Pong, Chat,
+                     PROTOCOL_VERSION};
+use model::message_pack;
+use model::game::{Id, Score, Goal, MatchState, RoomCode, Checksum};
+use outbox::OutboxStats;
+use error::Error;
+use util::{timestamp, SeqId, Time};
 
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
-use std::fmt::Debug;
+
+/// Clients we gave up on because they could not keep up with our messages
+pub type DroppedClients = Arc<RwLock<Vec<Id>>>;
+/// Pongs to send back to the client that pinged
+pub type PingQueue = Arc<RwLock<Vec<(Id, Pong)>>>;
+/// Chat messages to send to everyone in the room
+pub type ChatQueue = Arc<RwLock<Vec<Chat>>>;
+/// Round trip time of every client we measured one for
+pub type Latencies = Arc<RwLock<HashMap<Id, Time>>>;
 
 pub struct Sending;
 impl<'a> System<'a> for Sending {
     #[allow(type_complexity)]
     type SystemData = (ReadStorage<'a, Pos>,
      ReadStorage<'a, Vel>,
-     ReadStorage<'a, PlayerComp>,
+     WriteStorage<'a, PlayerComp>,
      ReadStorage<'a, Actor>,
      WriteStorage<'a, ToSpawn>,
-     ReadStorage<'a, ToDespawn>,
+     WriteStorage<'a, ToDespawn>,
+     ReadStorage<'a, Spectator>,
+     WriteStorage<'a, Promoted>,
+     WriteStorage<'a, Resumed>,
      Fetch<'a, SeqId>,
+     Fetch<'a, RwLock<Score>>,
+     Fetch<'a, RwLock<Vec<Goal>>>,
+     Fetch<'a, RwLock<MatchState>>,
+     Fetch<'a, RoomCode>,
+     Fetch<'a, RwLock<SnapshotHistory>>,
+     Fetch<'a, PingQueue>,
+     Fetch<'a, ChatQueue>,
+     Fetch<'a, DroppedClients>,
+     Fetch<'a, Latencies>,
+     Fetch<'a, Arc<RwLock<OutboxStats>>>,
+     Fetch<'a, RwLock<Option<Checksum>>>,
      Entities<'a>);
 
     fn run(&mut self, data: Self::SystemData) {
-        let (pos, vel, player, actor, mut connect, disconnect, curr_tick, entities) = data;
+        let (pos,
+             vel,
+             mut player,
+             actor,
+             mut connect,
+             mut disconnect,
+             spectator,
+             mut promoted,
+             mut resumed,
+             curr_tick,
+             score,
+             goals,
+             match_state,
+             room,
+             history,
+             pings,
+             chat,
+             dropped,
+             latencies,
+             outbox_stats,
+             checksum,
+             entities) = data;
+        let score = score.read().unwrap();
+        let mut match_state = match_state.write().unwrap();
 
-        handle_new_connections(&player, *curr_tick, &*entities, &actor, &mut connect);
-        handle_disconnects(&player, *curr_tick, &actor, &disconnect);
+        // Despawning them first lets everyone else know in this very tick
+        drop_lagging_clients(
+            &player,
+            &*entities,
+            &actor,
+            &mut disconnect,
+            &mut dropped.write().unwrap(),
+        );
+        handle_new_connections(
+            &player,
+            *curr_tick,
+            &*entities,
+            &actor,
+            &pos,
+            &spectator,
+            &score,
+            &match_state,
+            &room,
+            &mut connect,
+            &mut promoted,
+        );
+        handle_resumptions(
+            &player,
+            *curr_tick,
+            &*entities,
+            &actor,
+            &spectator,
+            &score,
+            &match_state,
+            &room,
+            &mut resumed,
+        );
+        handle_promotions(&player, *curr_tick, &*entities, &actor, &mut promoted);
+        handle_disconnects(
+            &player,
+            *curr_tick,
+            &*entities,
+            &actor,
+            &spectator,
+            &disconnect,
+        );
+        send_goals(&player, *curr_tick, &score, &mut goals.write().unwrap());
+        send_match_state(&player, *curr_tick, &score, &mut match_state);
+        send_pongs(&player, *curr_tick, &actor, &mut pings.write().unwrap());
+        send_chat(&player, *curr_tick, &mut chat.write().unwrap());
+        send_pings(&mut player, *curr_tick);
 
-        send_world_updates(&player, *curr_tick, &actor, &pos, &vel);
+        send_world_updates(
+            &player,
+            *curr_tick,
+            timestamp(),
+            &actor,
+            &pos,
+            &vel,
+            &mut history.write().unwrap(),
+            *checksum.read().unwrap(),
+        );
+        record_latencies(&player, &actor, &mut latencies.write().unwrap());
+        record_outbox_stats(&player, &mut outbox_stats.write().unwrap());
     }
 }
 
 
-fn send<T>(player: &PlayerComp, curr_tick: SeqId, msg: &ClientMessage<T>)
-where
-    T: Serialize + Debug,
-{
-    let mut msg =
-        serde_json::to_string(&msg).expect(&format!("Failed to serialize object {:?}", msg));
-    let send_channel = player.send_channel.clone();
-    let tick = format!(",\"tick\":{}", curr_tick);
-    let json_end_pos = msg.len() - 1;
-    msg.insert_str(json_end_pos, &tick);
-    send_channel.send(Message::Text(msg)).wait().expect(
-        "Failed to send message",
-    );
+fn send(player: &PlayerComp, msg: &ServerMsg) {
+    // Skipping a single message is better than taking the room down
+    match encode(player.format, msg) {
+        Ok(msg) => player.outbox.push(msg),
+        Err(err) => println!("{}", err),
+    }
+}
+
+fn encode(format: Format, msg: &ServerMsg) -> Result<Message, Error> {
+    match format {
+        Format::Json => {
+            let msg = serde_json::to_string(&msg).map_err(|err| {
+                Error::Encode(format!("{:?}: {}", msg, err))
+            })?;
+            Ok(Message::Text(msg))
+        }
+        Format::MessagePack => {
+            let encoded = message_pack::to_vec(msg).map_err(|err| {
+                Error::Encode(format!("{:?}: {}", msg, err))
+            })?;
+            Ok(Message::Binary(encoded))
+        }
+    }
 }
 
 
+fn drop_lagging_clients(
+    player: &WriteStorage<PlayerComp>,
+    entities: &EntitiesRes,
+    actor: &ReadStorage<Actor>,
+    disconnect: &mut WriteStorage<ToDespawn>,
+    dropped: &mut Vec<Id>,
+) {
+    let mut lagging = Vec::new();
+    for (entity, player, actor) in (&*entities, player, actor).join() {
+        if player.outbox.has_overflowed() && disconnect.get(entity).is_none() {
+            player.outbox.close();
+            lagging.push((entity, actor.id));
+        }
+    }
+    for (entity, id) in lagging {
+        disconnect.insert(entity, ToDespawn {});
+        dropped.push(id);
+    }
+}
+
+fn record_latencies(
+    player: &WriteStorage<PlayerComp>,
+    actor: &ReadStorage<Actor>,
+    latencies: &mut HashMap<Id, Time>,
+) {
+    latencies.clear();
+    for (player, actor) in (player, actor).join() {
+        if let Some(rtt) = player.latency.rtt() {
+            latencies.insert(actor.id, rtt);
+        }
+    }
+}
+
+fn record_outbox_stats(player: &WriteStorage<PlayerComp>, stats: &mut OutboxStats) {
+    let depths: Vec<usize> = player.join().map(|player| player.outbox.depth()).collect();
+    stats.queued = depths.iter().sum();
+    stats.deepest = depths.iter().cloned().max().unwrap_or(0);
+}
+
+#[allow(too_many_arguments)]
 fn handle_new_connections(
-    player: &ReadStorage<PlayerComp>,
+    player: &WriteStorage<PlayerComp>,
     curr_tick: SeqId,
     entities: &EntitiesRes,
     actor: &ReadStorage<Actor>,
+    pos: &ReadStorage<Pos>,
+    spectator: &ReadStorage<Spectator>,
+    score: &Score,
+    match_state: &MatchState,
+    room: &RoomCode,
     spawn: &mut WriteStorage<ToSpawn>,
+    promoted: &mut WriteStorage<Promoted>,
 ) {
     let mut new_connections = Vec::new();
     for (entity, actor, _) in (entities, actor, &mut *spawn).join() {
-        new_connections.push((entity, actor.clone()));
+        // Wait until we know whether they got a paddle or not
+        if pos.get(entity).is_some() || spectator.get(entity).is_some() {
+            new_connections.push((entity, actor.clone()));
+        }
     }
 
-    let mut actors = Vec::new();
-    for actor in (actor).join() {
-        actors.push(actor);
-    }
+    let actors = visible_actors(entities, actor, spectator);
     for new_connection in new_connections {
         let (new_entity, new_actor) = new_connection;
         spawn.remove(new_entity);
-        let greeting_msg = ClientMessage::new_greeting(&new_actor.id, &actors);
-        let other_spawn_msg = ClientMessage::new_spawn(&new_actor);
+        // The greeting already contains the new paddle
+        promoted.remove(new_entity);
+        let is_spectator = spectator.get(new_entity).is_some();
+        let other_spawn_msg = ServerMsg::new(curr_tick, ServerMessage::Spawn(&new_actor));
         for (player, entity) in (player, entities).join() {
             if entity == new_entity {
-                send(player, curr_tick, &greeting_msg);
-            } else {
-                send(player, curr_tick, &other_spawn_msg);
+                greet(player, curr_tick, &new_actor, &actors, score, match_state, room);
+            } else if !is_spectator {
+                send(player, &other_spawn_msg);
             }
         }
     }
 }
 
+/// Everyone else still knows about resumed players, only they need to catch up
+#[allow(too_many_arguments)]
+fn handle_resumptions(
+    player: &WriteStorage<PlayerComp>,
+    curr_tick: SeqId,
+    entities: &EntitiesRes,
+    actor: &ReadStorage<Actor>,
+    spectator: &ReadStorage<Spectator>,
+    score: &Score,
+    match_state: &MatchState,
+    room: &RoomCode,
+    resumed: &mut WriteStorage<Resumed>,
+) {
+    let resumptions: Vec<Entity> = (entities, &*resumed)
+        .join()
+        .map(|(entity, _)| entity)
+        .collect();
+    if resumptions.is_empty() {
+        return;
+    }
+    let actors = visible_actors(entities, actor, spectator);
+    for entity in resumptions {
+        resumed.remove(entity);
+        if let (Some(player), Some(actor)) = (player.get(entity), actor.get(entity)) {
+            greet(player, curr_tick, actor, &actors, score, match_state, room);
+        }
+    }
+}
+
+fn visible_actors<'a>(
+    entities: &EntitiesRes,
+    actor: &'a ReadStorage<Actor>,
+    spectator: &ReadStorage<Spectator>,
+) -> Vec<&'a Actor> {
+    let mut actors = Vec::new();
+    for (entity, actor) in (entities, actor).join() {
+        if spectator.get(entity).is_none() {
+            actors.push(actor);
+        }
+    }
+    actors
+}
+
+#[allow(too_many_arguments)]
+fn greet(
+    player: &PlayerComp,
+    curr_tick: SeqId,
+    own_actor: &Actor,
+    actors: &[&Actor],
+    score: &Score,
+    match_state: &MatchState,
+    room: &RoomCode,
+) {
+    let greeting = Greeting {
+        version: PROTOCOL_VERSION,
+        id: &own_actor.id,
+        actors,
+        score,
+        room,
+        session: &player.session,
+    };
+    send(player, &ServerMsg::new(curr_tick, ServerMessage::Greeting(greeting)));
+    let state_update = MatchStateUpdate::new(match_state, score);
+    send(player, &ServerMsg::new(curr_tick, ServerMessage::MatchState(state_update)));
+}
+
+fn handle_promotions(
+    player: &WriteStorage<PlayerComp>,
+    curr_tick: SeqId,
+    entities: &EntitiesRes,
+    actor: &ReadStorage<Actor>,
+    promoted: &mut WriteStorage<Promoted>,
+) {
+    let mut promotions = Vec::new();
+    for (entity, actor, _) in (entities, actor, &*promoted).join() {
+        promotions.push((entity, actor.clone()));
+    }
+    for (entity, actor) in promotions {
+        promoted.remove(entity);
+        let msg = ServerMsg::new(curr_tick, ServerMessage::Spawn(&actor));
+        for player in (player).join() {
+            send(player, &msg);
+        }
+    }
+}
+
 fn handle_disconnects(
-    player: &ReadStorage<PlayerComp>,
+    player: &WriteStorage<PlayerComp>,
     curr_tick: SeqId,
+    entities: &EntitiesRes,
     actor: &ReadStorage<Actor>,
-    disconnect: &ReadStorage<ToDespawn>,
+    spectator: &ReadStorage<Spectator>,
+    disconnect: &WriteStorage<ToDespawn>,
 ) {
-    for (actor, _) in (actor, disconnect).join() {
-        let msg = ClientMessage::new_despawn(&actor.id);
+    for (entity, actor, _) in (entities, actor, disconnect).join() {
+        // Nobody knew about spectators in the first place
+        if spectator.get(entity).is_some() {
+            continue;
+        }
+        let msg = ServerMsg::new(curr_tick, ServerMessage::Despawn(&actor.id));
         for player in (player).join() {
-            send(player, curr_tick, &msg);
+            send(player, &msg);
         }
     }
 
 }
 
+fn send_goals(
+    player: &WriteStorage<PlayerComp>,
+    curr_tick: SeqId,
+    score: &Score,
+    goals: &mut Vec<Goal>,
+) {
+    for goal in goals.drain(..) {
+        let update = ScoreUpdate::new(&goal, score);
+        let msg = ServerMsg::new(curr_tick, ServerMessage::Score(update));
+        for player in (player).join() {
+            send(player, &msg);
+        }
+    }
+}
+
+fn send_match_state(
+    player: &WriteStorage<PlayerComp>,
+    curr_tick: SeqId,
+    score: &Score,
+    match_state: &mut MatchState,
+) {
+    if match_state.take_changed() {
+        let update = MatchStateUpdate::new(match_state, score);
+        let msg = ServerMsg::new(curr_tick, ServerMessage::MatchState(update));
+        for player in (player).join() {
+            send(player, &msg);
+        }
+    }
+}
+
+#[allow(too_many_arguments)]
 fn send_world_updates(
-    player: &ReadStorage<PlayerComp>,
+    player: &WriteStorage<PlayerComp>,
     curr_tick: SeqId,
+    now: Time,
     actor: &ReadStorage<Actor>,
     pos: &ReadStorage<Pos>,
     vel: &ReadStorage<Vel>,
+    history: &mut SnapshotHistory,
+    checksum: Option<Checksum>,
 ) {
-    let mut serialized_actors = HashMap::new();
-    for actor in (actor).join() {
-        serialized_actors.insert(actor.id, HashMap::new());
-    }
-
+    let mut snapshot = Snapshot::new();
     for (pos, vel, actor) in (pos, vel, actor).join() {
-        let mut actor = serialized_actors.get_mut(&actor.id).unwrap();
-        actor.insert("pos", json!(pos));
-        actor.insert("vel", json!(vel));
+        let state = ActorState {
+            pos: pos.clone(),
+            vel: vel.clone(),
+        };
+        snapshot.insert(actor.id, state);
     }
+    history.push(curr_tick, snapshot);
+    let snapshot = history.latest().expect(
+        "Failed to send world update: Snapshot history is empty",
+    );
 
-    let json_actors = json!(serialized_actors);
     for player in (player).join() {
-        let last_input = json!(player.last_input);
-        let payload =
-            hashmap!(
-            "last_input" => &last_input,
-            "actors" => &json_actors
-        );
-        let world_state = ClientMessage {
-            opcode: OpCode::WorldUpdate,
-            payload: &payload,
-        };
-        send(player, curr_tick, &world_state);
+        let baseline = history.baseline(player.acked_tick);
+        let mut update = WorldUpdate::new(now, player.last_input, snapshot, baseline);
+        update.checksum = checksum.and_then(|checksum| if checksum.tick == curr_tick {
+            Some(checksum.value)
+        } else {
+            None
+        });
+        let msg = ServerMsg::new(curr_tick, ServerMessage::WorldUpdate(update));
+        send(player, &msg);
+    }
+}
+
+fn send_pongs(
+    player: &WriteStorage<PlayerComp>,
+    curr_tick: SeqId,
+    actor: &ReadStorage<Actor>,
+    pings: &mut Vec<(Id, Pong)>,
+) {
+    for (id, pong) in pings.drain(..) {
+        let msg = ServerMsg::new(curr_tick, ServerMessage::Pong(pong));
+        for (player, actor) in (player, actor).join() {
+            if actor.id == id {
+                send(player, &msg);
+            }
+        }
+    }
+}
+
+fn send_pings(player: &mut WriteStorage<PlayerComp>, curr_tick: SeqId) {
+    let now = timestamp();
+    for player in (&mut *player).join() {
+        if let Some(id) = player.latency.start_ping(now) {
+            let msg = ServerMsg::new(curr_tick, ServerMessage::Ping(Ping { id }));
+            send(player, &msg);
+        }
+    }
+}
+
+fn send_chat(player: &WriteStorage<PlayerComp>, curr_tick: SeqId, chat: &mut Vec<Chat>) {
+    for chat in chat.drain(..) {
+        let msg = ServerMsg::new(curr_tick, ServerMessage::Chat(&chat));
+        for player in (player).join() {
+            send(player, &msg);
+        }
     }
 }
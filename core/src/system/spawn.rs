@@ -1,9 +1,13 @@
 extern crate specs;
 use self::specs::{Join, WriteStorage, ReadStorage, System, Entities, Entity, Fetch};
 
-use model::comp::{Actor, ActorKind, Pos, Vel, Acc, ToSpawn, Bounciness};
-use model::game::{Vector, Id};
+use model::comp::{Actor, ActorKind, Pos, Vel, Acc, ToSpawn, ToDespawn, Bounciness, Paddle,
+                  Spectator, Promoted, Bot, Suspended};
+use model::game::{Vector, Id, Side, Slots};
+use model::fixed::Fixed;
 use collision::{World, Bounds};
+use config::GameConfig;
+use error::Error;
 use std::sync::RwLock;
 
 pub struct Spawn;
@@ -11,78 +15,220 @@ impl<'a> System<'a> for Spawn {
     #[allow(type_complexity)]
     type SystemData = (Entities<'a>,
      ReadStorage<'a, ToSpawn>,
+     WriteStorage<'a, ToDespawn>,
      ReadStorage<'a, Actor>,
+     ReadStorage<'a, Bot>,
+     ReadStorage<'a, Suspended>,
 
      WriteStorage<'a, Pos>,
      WriteStorage<'a, Vel>,
      WriteStorage<'a, Acc>,
      WriteStorage<'a, Bounciness>,
+     WriteStorage<'a, Paddle>,
+     WriteStorage<'a, Spectator>,
+     WriteStorage<'a, Promoted>,
 
-     Fetch<'a, RwLock<World<Id>>>);
+     Fetch<'a, RwLock<Slots>>,
+     Fetch<'a, RwLock<World<Id>>>,
+     Fetch<'a, GameConfig>);
 
     fn run(&mut self, data: Self::SystemData) {
-        let (entities, to_spawn, actor, mut pos, mut vel, mut acc, mut bounciness, world) = data;
+        let (entities,
+             to_spawn,
+             mut to_despawn,
+             actor,
+             bot,
+             suspended,
+             mut pos,
+             mut vel,
+             mut acc,
+             mut bounciness,
+             mut paddle,
+             mut spectator,
+             mut promoted,
+             slots,
+             world,
+             config) = data;
+        let mut slots = slots.write().unwrap();
         let mut world = world.write().unwrap();
+        let mut faulty = Vec::new();
+
+        // Spectators have been waiting longer than new connections, unless they went away
+        let mut waiting = Vec::new();
+        for (entity, actor, _) in (&*entities, &actor, &spectator).join() {
+            if to_despawn.get(entity).is_none() && suspended.get(entity).is_none() {
+                waiting.push((entity, actor.clone()));
+            }
+        }
+        for (entity, actor) in waiting {
+            if let Some(side) = slots.claim(actor.id) {
+                spectator.remove(entity);
+                promoted.insert(entity, Promoted {});
+                let spawned = spawn_paddle(
+                    entity,
+                    &actor,
+                    side,
+                    config.paddle.speed,
+                    &config,
+                    &mut acc,
+                    &mut vel,
+                    &mut pos,
+                    &mut paddle,
+                    &mut world,
+                );
+                if let Err(err) = spawned {
+                    faulty.push((entity, actor.id, err));
+                }
+            }
+        }
+
         for (entity, actor, _) in (&*entities, &actor, &to_spawn).join() {
-            match actor.kind {
+            // We might run multiple times before the spawn has been sent
+            if pos.get(entity).is_some() || spectator.get(entity).is_some() ||
+                to_despawn.get(entity).is_some()
+            {
+                continue;
+            }
+            let spawned = match actor.kind {
                 ActorKind::Player => {
-                    spawn_player(entity, actor, &mut acc, &mut vel, &mut pos, &mut world)
+                    match slots.claim(actor.id) {
+                        Some(side) => {
+                            spawn_paddle(
+                                entity,
+                                actor,
+                                side,
+                                config.paddle.speed,
+                                &config,
+                                &mut acc,
+                                &mut vel,
+                                &mut pos,
+                                &mut paddle,
+                                &mut world,
+                            )
+                        }
+                        None => {
+                            spectator.insert(entity, Spectator {});
+                            Ok(())
+                        }
+                    }
+                }
+                ActorKind::Bot => {
+                    let speed = match bot.get(entity) {
+                        Some(bot) => bot.difficulty.max_speed,
+                        None => {
+                            faulty.push((
+                                entity,
+                                actor.id,
+                                Error::MissingComponent("Bot".to_string()),
+                            ));
+                            continue;
+                        }
+                    };
+                    match slots.claim(actor.id) {
+                        Some(side) => {
+                            spawn_paddle(
+                                entity,
+                                actor,
+                                side,
+                                speed,
+                                &config,
+                                &mut acc,
+                                &mut vel,
+                                &mut pos,
+                                &mut paddle,
+                                &mut world,
+                            )
+                        }
+                        // A human was faster, nobody has heard of us yet
+                        None => {
+                            entities.delete(entity);
+                            Ok(())
+                        }
+                    }
                 }
                 ActorKind::Ball => {
                     spawn_ball(
                         entity,
                         actor,
+                        &config,
                         &mut vel,
                         &mut pos,
                         &mut bounciness,
                         &mut world,
                     )
                 }
+            };
+            if let Err(err) = spawned {
+                faulty.push((entity, actor.id, err));
             }
         }
+
+        // Better to lose a single actor than the whole room
+        for (entity, id, err) in faulty {
+            println!("Failed to spawn actor {}, despawning it: {}", id, err);
+            to_despawn.insert(entity, ToDespawn {});
+        }
     }
 }
 
-fn spawn_player(
+#[allow(too_many_arguments)]
+fn spawn_paddle(
     entity: Entity,
     actor: &Actor,
+    side: Side,
+    speed: Fixed,
+    config: &GameConfig,
     acc: &mut WriteStorage<Acc>,
     vel: &mut WriteStorage<Vel>,
     pos: &mut WriteStorage<Pos>,
+    paddle: &mut WriteStorage<Paddle>,
     world: &mut World<Id>,
-) {
-    let x = if entity.id() % 2 == 0 { 20 } else { 980 };
-    let y = 500;
-    acc.insert(entity, Acc::from(Vector { x: 0, y: 0 }));
-    vel.insert(entity, Vel::from(Vector { x: 0, y: 0 }));
-    pos.insert(entity, Pos::from(Vector { x, y }));
+) -> Result<(), Error> {
+    let x = match side {
+        Side::Left => config.paddle.inset,
+        Side::Right => config.arena.width - config.paddle.inset,
+    };
+    let y = config.arena.height / 2;
     let bounds = Bounds {
         x,
         y,
-        width: 15,
-        height: 75,
+        width: config.paddle.width,
+        height: config.paddle.height,
     };
-    world.add(actor.id, bounds);
+    world.try_add_with(actor.id, bounds, actor.kind.shape(), actor.kind.layers())?;
+    acc.insert(entity, Acc::from(Vector::new(0, 0)));
+    vel.insert(entity, Vel::from(Vector::new(0, 0)));
+    pos.insert(entity, Pos::from(Vector::new(x, y)));
+    paddle.insert(
+        entity,
+        Paddle {
+            side,
+            speed,
+            lag_ticks: 0,
+        },
+    );
+    Ok(())
 }
 
 fn spawn_ball(
     entity: Entity,
     actor: &Actor,
+    config: &GameConfig,
     vel: &mut WriteStorage<Vel>,
     pos: &mut WriteStorage<Pos>,
     bounciness: &mut WriteStorage<Bounciness>,
     world: &mut World<Id>,
-) {
-    let x = 500;
-    let y = 500;
-    vel.insert(entity, Vel::from(Vector { x: 7, y: 11 }));
-    pos.insert(entity, Pos::from(Vector { x, y: 500 }));
-    bounciness.insert(entity, Bounciness {});
+) -> Result<(), Error> {
+    let center = config.arena.center();
     let bounds = Bounds {
-        x,
-        y,
-        width: 15,
-        height: 15,
+        x: center.x.round(),
+        y: center.y.round(),
+        width: config.ball.size,
+        height: config.ball.size,
     };
-    world.add(actor.id, bounds);
+    world.try_add_with(actor.id, bounds, actor.kind.shape(), actor.kind.layers())?;
+    vel.insert(entity, Vel::from(config.ball.serve.clone()));
+    pos.insert(entity, Pos::from(center));
+    bounciness.insert(entity, Bounciness {});
+    Ok(())
 }
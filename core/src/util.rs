@@ -1,21 +1,45 @@
 extern crate chrono;
+extern crate uuid;
 
 
 use self::chrono::{DateTime, Utc};
+use self::uuid::Uuid;
 use std::env;
+use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 use model::game::Vector;
-use std::f64::consts;
+use model::fixed::Fixed;
+use error::Error;
 
-pub fn read_env_var(var: &str) -> String {
+pub fn read_env_var(var: &str) -> Result<String, Error> {
     env::var_os(var)
-        .expect(&format!(
-            "{} must be specified. \
-             Did you forget to add it to your .env file?",
-            var
-        ))
+        .ok_or_else(|| Error::MissingEnvVar(var.to_string()))?
         .into_string()
-        .expect(&format!("{} does not contain a valid UTF8 string", var))
+        .map_err(|_| Error::InvalidEnvVar(var.to_string()))
+}
+
+/// Parses the environmental variable if it is set
+pub fn parse_env_var<T>(var: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+{
+    if env::var_os(var).is_none() {
+        return Ok(None);
+    }
+    read_env_var(var)?.parse::<T>().map(Some).map_err(|_| {
+        Error::InvalidEnvVar(var.to_string())
+    })
+}
+
+/// Replaces the value if the environmental variable is set
+pub fn override_from_env<T>(var: &str, val: &mut T) -> Result<(), Error>
+where
+    T: FromStr,
+{
+    if let Some(parsed) = parse_env_var(var)? {
+        *val = parsed;
+    }
+    Ok(())
 }
 
 
@@ -33,20 +57,20 @@ pub fn timestamp() -> Time {
 
 
 
-pub fn angle(a: &Vector, b: &Vector) -> f64 {
-    assert_ne!(
-        *a,
-        *b,
-        "Failed to calculate angle between two points: Points are equivalent"
-    );
-    let d_x = b.x as f64 - a.x as f64;
+/// In degrees, from 0 up to 360. A point has no angle to itself.
+pub fn angle(a: &Vector, b: &Vector) -> Option<Fixed> {
+    if *a == *b {
+        return None;
+    }
+    let d_x = b.x - a.x;
     // Swapped because a positive y means down for us
-    let d_y = a.y as f64 - b.y as f64;
-    let mut rad = d_y.atan2(d_x);
-    while rad < 0.0 {
-        rad += consts::PI * 2.0
+    let d_y = a.y - b.y;
+    let degrees = Fixed::atan2_deg(d_y, d_x);
+    if degrees < Fixed::zero() {
+        Some(degrees + Fixed::from(360))
+    } else {
+        Some(degrees)
     }
-    rad.to_degrees()
 }
 
 pub fn clamp<T>(val: T, min: T, max: T) -> T
@@ -72,6 +96,132 @@ impl SeqIdGen {
     }
 }
 
+/// Generates entity ids, either randomly or as a reproducible sequence
+pub enum IdGen {
+    Random,
+    Seeded(u64),
+}
+impl Default for IdGen {
+    fn default() -> Self {
+        IdGen::Random
+    }
+}
+impl IdGen {
+    /// Generators with the same seed but different streams never share ids
+    pub fn seeded(seed: u64, stream: u64) -> Self {
+        let mut stream_state = stream;
+        IdGen::Seeded(seed ^ splitmix(&mut stream_state))
+    }
+
+    pub fn gen(&mut self) -> Uuid {
+        match *self {
+            IdGen::Random => Uuid::new_v4(),
+            IdGen::Seeded(ref mut state) => {
+                let mut bytes = [0; 16];
+                for chunk in bytes.chunks_mut(8) {
+                    let num = splitmix(state);
+                    for (i, byte) in chunk.iter_mut().enumerate() {
+                        *byte = (num >> (i * 8)) as u8;
+                    }
+                }
+                Uuid::from_bytes(&bytes).expect("Failed to generate id: Invalid length")
+            }
+        }
+    }
+}
+
+fn splitmix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut num = *state;
+    num = (num ^ (num >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    num = (num ^ (num >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    num ^ (num >> 31)
+}
+
+
+pub const FNV_OFFSET: u32 = 0x811C_9DC5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/// Feeds bytes into a 32 bit FNV-1a hash, start with `FNV_OFFSET`
+pub fn fnv1a(hash: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Token bucket that allows bursts of up to a second's worth of events,
+/// as long as they average out below the rate
+pub struct RateLimiter {
+    per_sec: u32,
+    /// In thousandths of an event, so slow refills don't get rounded away
+    milli_tokens: u64,
+    last_refill: Time,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: u32, now: Time) -> Self {
+        RateLimiter {
+            per_sec,
+            milli_tokens: u64::from(per_sec) * 1000,
+            last_refill: now,
+        }
+    }
+
+    /// Uses up a token if there is one left
+    pub fn allow(&mut self, now: Time) -> bool {
+        let capacity = u64::from(self.per_sec) * 1000;
+        let elapsed = now.saturating_sub(self.last_refill);
+        self.milli_tokens = capacity.min(
+            self.milli_tokens + elapsed * u64::from(self.per_sec),
+        );
+        self.last_refill = self.last_refill.max(now);
+        if self.milli_tokens >= 1000 {
+            self.milli_tokens -= 1000;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Spreads `rate` ticks evenly over every `period` units of time, like 60 ticks per 1000 ms.
+/// Never drifts, even if a single tick doesn't last a whole number of units.
+pub struct Ticker {
+    rate: u64,
+    period: u64,
+    /// Only counted since the start of the current period
+    elapsed: u64,
+    ticks: u64,
+}
+
+impl Ticker {
+    pub fn new(rate: u64, period: u64) -> Self {
+        Ticker {
+            rate,
+            period,
+            elapsed: 0,
+            ticks: 0,
+        }
+    }
+
+    /// Lets time pass, returns how many ticks became due
+    pub fn advance(&mut self, elapsed: u64) -> u64 {
+        self.elapsed += elapsed;
+        let due = self.elapsed * self.rate / self.period - self.ticks;
+        self.ticks += due;
+        let periods = self.elapsed / self.period;
+        self.elapsed -= periods * self.period;
+        self.ticks -= periods * self.rate;
+        due
+    }
+
+    /// Time left until the next tick is due
+    pub fn until_next(&self) -> u64 {
+        let next = ((self.ticks + 1) * self.period + self.rate - 1) / self.rate;
+        next.saturating_sub(self.elapsed)
+    }
+}
+
 
 #[macro_export]
 macro_rules! newtype {
@@ -120,14 +270,27 @@ mod test {
     #[test]
     fn read_string_envvar() {
         env::set_var("TEST", "foo");
-        assert_eq!("foo", &read_env_var("TEST"));
+        assert_eq!(Ok("foo".to_string()), read_env_var("TEST"));
     }
 
     #[test]
-    #[should_panic]
     fn read_empty_envvar() {
         env::remove_var("EMPTY");
-        read_env_var("EMPTY");
+        assert_eq!(
+            Err(Error::MissingEnvVar("EMPTY".to_string())),
+            read_env_var("EMPTY")
+        );
+    }
+
+    #[test]
+    fn parse_invalid_envvar() {
+        env::set_var("INVALID", "foo");
+        assert_eq!(
+            Err(Error::InvalidEnvVar("INVALID".to_string())),
+            parse_env_var::<u32>("INVALID")
+        );
+        env::remove_var("INVALID");
+        assert_eq!(Ok(None), parse_env_var::<u32>("INVALID"));
     }
 
 
@@ -169,42 +332,41 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
     fn angle_same() {
-        let a = Vector { x: 0, y: 0 };
+        let a = Vector::new(0, 0);
         let b = a.clone();
-        angle(&a, &b);
+        assert_eq!(None, angle(&a, &b));
     }
 
     #[test]
     fn angle_right() {
-        let a = Vector { x: 0, y: 0 };
-        let b = Vector { x: 1, y: 0 };
-        assert_eq!(0.0, angle(&a, &b));
+        let a = Vector::new(0, 0);
+        let b = Vector::new(1, 0);
+        assert_eq!(Some(Fixed::zero()), angle(&a, &b));
     }
 
 
     #[test]
     fn angle_down() {
-        let a = Vector { x: 0, y: 0 };
-        let b = Vector { x: 0, y: 1 };
-        assert_eq!(270.0, angle(&a, &b));
+        let a = Vector::new(0, 0);
+        let b = Vector::new(0, 1);
+        assert_eq!(Some(Fixed::from(270)), angle(&a, &b));
     }
 
 
     #[test]
     fn angle_left() {
-        let a = Vector { x: 0, y: 0 };
-        let b = Vector { x: -1, y: 0 };
-        assert_eq!(180.0, angle(&a, &b));
+        let a = Vector::new(0, 0);
+        let b = Vector::new(-1, 0);
+        assert_eq!(Some(Fixed::from(180)), angle(&a, &b));
     }
 
 
     #[test]
     fn angle_up() {
-        let a = Vector { x: 0, y: 0 };
-        let b = Vector { x: 0, y: -1 };
-        assert_eq!(90.0, angle(&a, &b));
+        let a = Vector::new(0, 0);
+        let b = Vector::new(0, -1);
+        assert_eq!(Some(Fixed::from(90)), angle(&a, &b));
     }
 
 
@@ -239,6 +401,75 @@ mod test {
     }
 
 
+    #[test]
+    fn id_gen_seeded_reproducible() {
+        let mut a = IdGen::seeded(42, 0);
+        let mut b = IdGen::seeded(42, 0);
+        for _ in 0..100 {
+            assert_eq!(a.gen(), b.gen());
+        }
+    }
+
+    #[test]
+    fn id_gen_streams_differ() {
+        let mut a = IdGen::seeded(42, 0);
+        let mut b = IdGen::seeded(42, 1);
+        let ids: Vec<_> = (0..100).map(|_| a.gen()).collect();
+        for _ in 0..100 {
+            assert!(!ids.contains(&b.gen()));
+        }
+    }
+
+    #[test]
+    fn fnv1a_known_values() {
+        assert_eq!(0x811C_9DC5, fnv1a(FNV_OFFSET, b""));
+        assert_eq!(0xE40C_292C, fnv1a(FNV_OFFSET, b"a"));
+        assert_eq!(0xBF9C_F968, fnv1a(FNV_OFFSET, b"foobar"));
+    }
+
+    #[test]
+    fn rate_limiter_allows_burst() {
+        let mut limiter = RateLimiter::new(10, 0);
+        for _ in 0..10 {
+            assert!(limiter.allow(0));
+        }
+        assert!(!limiter.allow(0));
+    }
+
+    #[test]
+    fn rate_limiter_refills() {
+        let mut limiter = RateLimiter::new(10, 0);
+        while limiter.allow(0) {}
+        assert!(!limiter.allow(99));
+        assert!(limiter.allow(100));
+        assert!(!limiter.allow(100));
+        // Never holds more than a second's worth
+        let allowed = (0..100).take_while(|_| limiter.allow(60_000)).count();
+        assert_eq!(10, allowed);
+    }
+
+    #[test]
+    fn ticker_spreads_ticks() {
+        let mut ticker = Ticker::new(60, 1000);
+        assert_eq!(17, ticker.until_next());
+        assert_eq!(0, ticker.advance(16));
+        assert_eq!(1, ticker.until_next());
+        assert_eq!(1, ticker.advance(1));
+        assert_eq!(59, ticker.advance(983));
+        // Sending every third update
+        let mut ticker = Ticker::new(20, 60);
+        let due: Vec<u64> = (0..6).map(|_| ticker.advance(1)).collect();
+        assert_eq!(vec![0, 0, 1, 0, 0, 1], due);
+    }
+
+    #[test]
+    fn ticker_does_not_drift() {
+        let mut ticker = Ticker::new(60, 1000);
+        let ticks: u64 = (0..10_000).map(|_| ticker.advance(1)).sum();
+        assert_eq!(600, ticks);
+        assert_eq!(120, ticker.advance(2000));
+    }
+
     #[test]
     fn seq_id_gen_sequential() {
         let mut id_gen = SeqIdGen::default();
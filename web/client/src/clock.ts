@@ -0,0 +1,67 @@
+// Estimates about our connection to the server, all in milliseconds
+
+let rtt: number | null = null
+// Server time minus local time
+let offset: number | null = null
+// How often the server sends snapshots as we observe it
+let updateInterval = 1000 / 30
+// The server may simulate several ticks per snapshot
+let tickInterval = 1000 / 30
+let lastServerTime: number | null = null
+let lastServerTick: number | null = null
+// The latest tick we know of and when it happened in local time
+let lastTick: { tick: number, timestamp: number } | null = null
+// Inputs arriving this many ticks early survive some jitter
+const INPUT_MARGIN_TICKS = 1
+
+export function onRoundTrip (sample: number): void {
+    rtt = rtt === null ? sample : 0.875 * rtt + 0.125 * sample
+}
+
+// Converts the server time of a snapshot into our local time
+export function onSnapshot (serverTime: number, tick: number): number {
+    const now = performance.now()
+    // The snapshot is half a round trip old when it arrives
+    const sample = serverTime + (rtt || 0) / 2 - now
+    offset = offset === null ? sample : 0.9 * offset + 0.1 * sample
+    if (lastServerTime !== null && lastServerTick !== null && serverTime > lastServerTime) {
+        const interval = serverTime - lastServerTime
+        updateInterval = 0.9 * updateInterval + 0.1 * interval
+        if (tick > lastServerTick) {
+            tickInterval = 0.9 * tickInterval + 0.1 * interval / (tick - lastServerTick)
+        }
+    }
+    lastServerTime = serverTime
+    lastServerTick = tick
+    const timestamp = serverTime - offset
+    if (lastTick === null || tick > lastTick.tick) {
+        lastTick = { tick, timestamp }
+    }
+    return timestamp
+}
+
+// The tick an input sent right now should be applied in
+export function getInputTick (): number {
+    if (lastTick === null) {
+        return 0
+    }
+    const elapsed = performance.now() - lastTick.timestamp
+    const inFlight = (rtt || 0) / 2
+    const ticksAhead = Math.ceil((elapsed + inFlight) / tickInterval)
+    return lastTick.tick + ticksAhead + INPUT_MARGIN_TICKS
+}
+
+export function getRtt (): number | null {
+    return rtt
+}
+
+export function getUpdateInterval (): number {
+    return updateInterval
+}
+
+export function reset (): void {
+    offset = null
+    lastServerTime = null
+    lastServerTick = null
+    lastTick = null
+}
@@ -1,27 +1,55 @@
+import * as Clock from "./clock"
 import * as Display from "./display"
 import * as Globals from "./globals"
+import * as MsgPack from "./msgpack"
 import * as Types from "./types"
 
 let io: WebSocket | null
+let pingTimer: number | null = null
+let nextPingId = 0
+const pendingPings = new Map<number, number>()
 const MIN_WAIT = 100
+// Has to match the server, older baselines are never referenced
+const MAX_SNAPSHOT_AGE = 32
+const PROTOCOL_VERSION = 1
+const SESSION_KEY = "session"
+// Sent by the server when it does not speak our protocol
+const PROTOCOL_ERROR = 1002
 let wait = MIN_WAIT
 export function connect (address: string): void {
     io = new WebSocket(address)
+    io.binaryType = "arraybuffer"
     io.onopen = () => {
         resetWait()
         Globals.connectionInfo.visible = false
+        sayHello()
+        requestRoom()
+        pingTimer = window.setInterval(ping, 1000)
     }
 
     io.onmessage = (serializedMsg) => {
-        const msg: Types.IServerMessage = JSON.parse(serializedMsg.data, (_, value) => value === "" ? 0 : value)
+        const msg: Types.IServerMessage = typeof serializedMsg.data === "string"
+            ? JSON.parse(serializedMsg.data, (_, value) => value === "" ? 0 : value)
+            : MsgPack.decode(serializedMsg.data)
 
         switch (msg.opcode) {
         case Types.OpCode.Greeting:
-            Globals.setOwnId(msg.payload[0])
-            const presentActors = msg.payload[1]
-            for (const actor of presentActors) {
+            // We might have switched rooms, so forget about the old one
+            for (const [id, _] of Globals.actors) {
+                Display.removeActor(id)
+            }
+            Globals.states.splice(0, Globals.states.length)
+            Globals.snapshots.clear()
+            Clock.reset()
+            const greeting: Types.IGreeting = msg.payload
+            Globals.setOwnId(greeting.id)
+            // Lets us take our paddle back if the connection drops
+            window.sessionStorage.setItem(SESSION_KEY, greeting.session)
+            for (const actor of greeting.actors) {
                 Display.spawnActor(actor)
             }
+            Display.setScore(greeting.score)
+            window.location.hash = greeting.room
             break
         case Types.OpCode.Spawn:
             Display.spawnActor(msg.payload)
@@ -30,10 +58,16 @@ export function connect (address: string): void {
             Display.removeActor(msg.payload)
             break
         case Types.OpCode.WorldUpdate:
+            const actors = applyWorldUpdate(msg.tick, msg.payload)
+            if (!actors) {
+                // Without an ack the server will eventually fall back to a full snapshot
+                break
+            }
+            send({ Ack: msg.tick })
             const state: Types.IState = {
-                actors: msg.payload.actors,
+                actors,
                 tick: msg.tick,
-                timestamp: performance.now(),
+                timestamp: Clock.onSnapshot(msg.payload.server_time, msg.tick),
             }
             Globals.states.push(state)
             const index = Globals.unconfirmedInputs.findIndex((input) => input.id === msg.payload.last_input) + 1
@@ -41,12 +75,44 @@ export function connect (address: string): void {
                 Globals.unconfirmedInputs.splice(0, index)
             }
             break
+        case Types.OpCode.Score:
+            Display.setScore(msg.payload.score)
+            break
+        case Types.OpCode.MatchState:
+            Display.setMatchState(msg.payload)
+            break
+        case Types.OpCode.Pong:
+            const sentAt = pendingPings.get(msg.payload.id)
+            if (sentAt !== undefined) {
+                pendingPings.delete(msg.payload.id)
+                Clock.onRoundTrip(performance.now() - sentAt)
+            }
+            break
+        case Types.OpCode.Ping:
+            send({ Pong: { id: msg.payload.id } })
+            break
+        case Types.OpCode.Chat:
+            const chat: Types.IChat = msg.payload
+            console.log(`${chat.from}: ${chat.text}`)
+            break
         default:
             throw new Error(`Received invalid opcode: ${msg.opcode}`)
         }
     }
 
-    io.onclose = () => {
+    io.onclose = (event) => {
+        if (pingTimer !== null) {
+            window.clearInterval(pingTimer)
+            pingTimer = null
+        }
+        pendingPings.clear()
+        if (event.code === PROTOCOL_ERROR) {
+            // Reconnecting would not change anything, we need a newer client
+            Globals.connectionInfo.text = event.reason
+            Globals.connectionInfo.visible = true
+            io = null
+            return
+        }
         Globals.connectionInfo.text = "Attempting to reconnect"
         Globals.connectionInfo.visible = true
         io = null
@@ -69,12 +135,61 @@ export function connect (address: string): void {
     }
 }
 
-export function send (data: Types.IClientMessage): void {
+export function send (data: Types.ClientMessage): void {
     if (io && io.readyState === 1) {
         io.send(JSON.stringify(data))
     }
 }
 
+function applyWorldUpdate (tick: number, update: Types.IWorldUpdate): any {
+    let actors = {}
+    if (update.baseline !== null) {
+        const baseline = Globals.snapshots.get(update.baseline)
+        if (!baseline) {
+            return null
+        }
+        actors = { ...baseline }
+    }
+    for (const id of Object.keys(update.actors)) {
+        actors[id] = update.actors[id]
+    }
+    for (const id of update.removed) {
+        delete actors[id]
+    }
+    Globals.snapshots.set(tick, actors)
+    for (const oldTick of Array.from(Globals.snapshots.keys())) {
+        if (oldTick <= tick - MAX_SNAPSHOT_AGE) {
+            Globals.snapshots.delete(oldTick)
+        }
+    }
+    return actors
+}
+
+function ping (): void {
+    const id = nextPingId++
+    pendingPings.set(id, performance.now())
+    send({ Ping: { id } })
+}
+
+function sayHello (): void {
+    // Keep the human readable format around for debugging by adding ?json to the URL
+    const format = window.location.search.indexOf("json") === -1
+        ? Types.Format.MessagePack
+        : Types.Format.Json
+    const session = window.sessionStorage.getItem(SESSION_KEY) || undefined
+    send({ Hello: { version: PROTOCOL_VERSION, format, session } })
+}
+
+function requestRoom (): void {
+    // The server puts us into any free room unless we ask for a specific one
+    const code = window.location.hash.substr(1)
+    if (code === "new") {
+        send({ Join: "Create" })
+    } else if (code) {
+        send({ Join: { Existing: code } })
+    }
+}
+
 function resetWait (): void {
     wait = MIN_WAIT
 }
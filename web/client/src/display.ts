@@ -35,6 +35,11 @@ export function spawnActor (actor: Types.IActor): void {
         height = 75
         width = 15
         break
+    case Types.ActorKind.Bot:
+        texture = "fancy-paddle-grey.png"
+        height = 75
+        width = 15
+        break
     case Types.ActorKind.Ball:
         texture = "fancy-ball.png"
         height = 15
@@ -65,9 +70,35 @@ export function removeActor (id: Types.Id): void {
     Globals.actors.delete(id)
 }
 
+export function setScore (score: Types.IScore): void {
+    Globals.scoreInfo.text = `${score.left} : ${score.right}`
+}
+
+export function setMatchState (state: Types.IMatchState): void {
+    setScore(state.score)
+    switch (state.phase) {
+    case Types.Phase.WaitingForPlayers:
+        Globals.phaseInfo.text = "Waiting for an opponent"
+        break
+    case Types.Phase.Countdown:
+        Globals.phaseInfo.text = "Get ready!"
+        break
+    case Types.Phase.Paused:
+        Globals.phaseInfo.text = "Paused"
+        break
+    case Types.Phase.Finished:
+        Globals.phaseInfo.text = `${state.winner} wins!`
+        break
+    default:
+        Globals.phaseInfo.text = ""
+    }
+    Globals.phaseInfo.visible = state.phase !== Types.Phase.Playing
+}
+
 export function setBlur (obj: PIXI.Sprite, vel: Types.IVector): void {
     const maxVel = Math.max(Math.abs(vel.x), Math.abs(vel.y))
-    const strength = Math.pow(Math.atan(Math.pow((maxVel / 10), 1.5)), 2) - 0.2
+    // Velocities are in units per second
+    const strength = Math.pow(Math.atan(Math.pow((maxVel / 300), 1.5)), 2) - 0.2
     if (strength > 0.5) {
         const blurFilter = new PIXI.filters.BlurFilter(strength, 1, 1)
         obj.filters = [blurFilter]
@@ -93,6 +124,25 @@ function pixiSetup (): void {
     Globals.connectionInfo.x = GAME_WIDTH - 300
     app.stage.addChild(Globals.connectionInfo)
 
+    Globals.setScoreInfo(new PIXI.Text("0 : 0"))
+    Globals.scoreInfo.style.fill = 0xe3e3ed
+    Globals.scoreInfo.style.fontSize = 48
+    Globals.scoreInfo.anchor.set(0.5, 0)
+    Globals.scoreInfo.y = 30
+    Globals.scoreInfo.x = GAME_WIDTH / 2
+    app.stage.addChild(Globals.scoreInfo)
+
+    Globals.setPhaseInfo(new PIXI.Text(""))
+    Globals.phaseInfo.style.fill = 0xe3e3ed
+    Globals.phaseInfo.style.fontSize = 64
+    Globals.phaseInfo.style.dropShadow = true
+    Globals.phaseInfo.style.dropShadowAlpha = 0.7
+    Globals.phaseInfo.anchor.set(0.5)
+    Globals.phaseInfo.x = GAME_WIDTH / 2
+    Globals.phaseInfo.y = GAME_HEIGHT / 2
+    Globals.phaseInfo.visible = false
+    app.stage.addChild(Globals.phaseInfo)
+
     resize()
     window.addEventListener("resize", resize)
 
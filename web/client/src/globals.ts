@@ -9,5 +9,15 @@ export let connectionInfo: PIXI.Text
 export function setConnectionInfo (sprite: PIXI.Text): void {
     connectionInfo = sprite
 }
+export let scoreInfo: PIXI.Text
+export function setScoreInfo (sprite: PIXI.Text): void {
+    scoreInfo = sprite
+}
+export let phaseInfo: PIXI.Text
+export function setPhaseInfo (sprite: PIXI.Text): void {
+    phaseInfo = sprite
+}
 export const states: Types.IState[] = []
-export const unconfirmedInputs: Types.IClientMessage[] = []
+// Every received snapshot by tick, so deltas can be applied to them
+export const snapshots = new Map<number, any>()
+export const unconfirmedInputs: Types.IInput[] = []
@@ -1,5 +1,7 @@
+import * as Clock from "./clock"
 import * as Connection from "./connection"
 import * as Globals from "./globals"
+import * as Interpolation from "./interpolation"
 import * as Types from "./types"
 
 const commandState = new Map<Types.Command, boolean>()
@@ -32,16 +34,19 @@ function codeToEvent (code: string): Types.Command | null {
     }
 }
 
-let msgId = 0
+// The server only accepts increasing ids and treats 0 as no input at all
+let msgId = 1
 function sendCommand (command: Types.Command, active: boolean): void {
     if (commandState.get(command) !== active) {
         commandState.set(command, active)
-        const msg: Types.IClientMessage = {
+        const input: Types.IInput = {
             active,
             command,
             id: msgId++,
+            seen_tick: Interpolation.getRenderTick(),
+            tick: Clock.getInputTick(),
         }
-        Globals.unconfirmedInputs.push(msg)
-        Connection.send(msg)
+        Globals.unconfirmedInputs.push(input)
+        Connection.send({ Input: input })
     }
 }
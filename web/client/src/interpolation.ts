@@ -1,10 +1,10 @@
+import * as Clock from "./clock"
 import * as Globals from "./globals"
 import * as Types from "./types"
 
 export function getRenderTime (): number {
     const lerpRatio = 2
-    const updateRate = 30
-    const delay = Math.floor(lerpRatio * 1000 / updateRate)
+    const delay = Math.floor(lerpRatio * Clock.getUpdateInterval())
     const now = Math.floor(performance.now())
     return now - delay
 }
@@ -14,6 +14,21 @@ export function getIndexOfRenderState (renderTime: number): number {
     return found - 1
 }
 
+// The tick of the state we are showing right now
+export function getRenderTick (): number {
+    const states = Globals.states
+    if (states.length === 0) {
+        return 0
+    }
+    const index = getIndexOfRenderState(getRenderTime())
+    if (index === -1) {
+        // Still waiting for the second state
+        return states[0].tick
+    }
+    // Past the latest state
+    return index < 0 ? states[states.length - 1].tick : states[index].tick
+}
+
 export function getInterpolatedState (from: Types.IState, to: Types.IState, renderTime: number): Types.IState {
     const total = to.timestamp - from.timestamp
     const progress = renderTime - from.timestamp
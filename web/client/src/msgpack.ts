@@ -0,0 +1,219 @@
+// Minimal MessagePack decoder covering everything the server sends.
+// Structs arrive as arrays of their fields and ids as 16 raw bytes,
+// the schemas below turn them into the same objects the JSON format contains.
+
+import * as Types from "./types"
+
+type Schema = (value: any) => any
+
+const plain: Schema = (value) => value
+
+function id (bytes: Uint8Array): Types.Id {
+    const hex = Array.from(bytes, (byte) => (byte < 0x10 ? "0" : "") + byte.toString(16)).join("")
+    return [hex.slice(0, 8), hex.slice(8, 12), hex.slice(12, 16), hex.slice(16, 20), hex.slice(20)].join("-")
+}
+
+// Variants without data arrive as [index, []]
+function variant (names: string[]): Schema {
+    return (value: any[]) => names[value[0]]
+}
+
+function nullable (schema: Schema): Schema {
+    return (value) => value === null ? null : schema(value)
+}
+
+function list (schema: Schema): Schema {
+    return (values: any[]) => values.map(schema)
+}
+
+// Arrives as an array with a single element
+function newtype (schema: Schema): Schema {
+    return (value: any[]) => schema(value[0])
+}
+
+function struct (fields: Array<[string, Schema]>): Schema {
+    return (values: any[]) => {
+        const result = {}
+        // Optional fields at the end might be left out
+        for (let i = 0; i < values.length; i++) {
+            const [name, schema] = fields[i]
+            result[name] = schema(values[i])
+        }
+        return result
+    }
+}
+
+function dict (key: Schema, value: Schema): Schema {
+    return (entries: Map<any, any>) => {
+        const result = {}
+        for (const [entryKey, entryValue] of entries) {
+            result[key(entryKey)] = value(entryValue)
+        }
+        return result
+    }
+}
+
+const side = variant([Types.Side.Left, Types.Side.Right])
+const score = struct([["left", plain], ["right", plain]])
+const actor = struct([
+    ["id", id],
+    ["kind", variant([Types.ActorKind.Player, Types.ActorKind.Ball, Types.ActorKind.Bot])],
+])
+const vector = struct([["x", plain], ["y", plain]])
+const actorState = struct([["pos", newtype(vector)], ["vel", newtype(vector)]])
+
+// Has to list the fields in the same order as the server declares them
+const payloads: { [opcode: string]: Schema } = {
+    [Types.OpCode.Greeting]: struct([
+        ["version", plain],
+        ["id", id],
+        ["actors", list(actor)],
+        ["score", score],
+        ["room", newtype(plain)],
+        ["session", id],
+    ]),
+    [Types.OpCode.Spawn]: actor,
+    [Types.OpCode.Despawn]: id,
+    [Types.OpCode.WorldUpdate]: struct([
+        ["server_time", plain],
+        ["last_input", plain],
+        ["baseline", plain],
+        ["actors", dict(id, actorState)],
+        ["removed", list(id)],
+        ["checksum", plain],
+    ]),
+    [Types.OpCode.Score]: struct([["scorer", side], ["score", score]]),
+    [Types.OpCode.MatchState]: struct([
+        ["phase", variant([
+            Types.Phase.WaitingForPlayers,
+            Types.Phase.Countdown,
+            Types.Phase.Playing,
+            Types.Phase.Paused,
+            Types.Phase.Finished,
+        ])],
+        ["winner", nullable(side)],
+        ["ticks_left", plain],
+        ["score", score],
+    ]),
+    [Types.OpCode.Pong]: struct([["id", plain]]),
+    [Types.OpCode.Ping]: struct([["id", plain]]),
+    [Types.OpCode.Chat]: struct([["from", id], ["text", plain]]),
+}
+
+export function decode (buffer: ArrayBuffer): Types.IServerMessage {
+    const reader = new Reader(new DataView(buffer))
+    const [opcode, payload, tick] = reader.read()
+    const schema = payloads[opcode]
+    if (!schema) {
+        throw new Error(`Received invalid opcode: ${opcode}`)
+    }
+    return { opcode, payload: schema(payload), tick }
+}
+
+class Reader {
+    private offset = 0
+
+    constructor (private view: DataView) {}
+
+    public read (): any {
+        const type = this.uint8()
+        if (type <= 0x7f) {
+            return type
+        }
+        if (type >= 0xe0) {
+            return type - 0x100
+        }
+        if ((type & 0xf0) === 0x80) {
+            return this.map(type & 0x0f)
+        }
+        if ((type & 0xf0) === 0x90) {
+            return this.array(type & 0x0f)
+        }
+        if ((type & 0xe0) === 0xa0) {
+            return this.str(type & 0x1f)
+        }
+        switch (type) {
+        case 0xc0: return null
+        case 0xc2: return false
+        case 0xc3: return true
+        case 0xc4: return this.bin(this.uint8())
+        case 0xc5: return this.bin(this.advance(2, this.view.getUint16(this.offset)))
+        case 0xc6: return this.bin(this.uint32())
+        case 0xca: return this.advance(4, this.view.getFloat32(this.offset))
+        case 0xcb: return this.advance(8, this.view.getFloat64(this.offset))
+        case 0xcc: return this.uint8()
+        case 0xcd: return this.advance(2, this.view.getUint16(this.offset))
+        case 0xce: return this.uint32()
+        case 0xcf: return this.uint32() * 0x100000000 + this.uint32()
+        case 0xd0: return this.advance(1, this.view.getInt8(this.offset))
+        case 0xd1: return this.advance(2, this.view.getInt16(this.offset))
+        case 0xd2: return this.advance(4, this.view.getInt32(this.offset))
+        case 0xd3: return this.advance(4, this.view.getInt32(this.offset)) * 0x100000000 + this.uint32()
+        case 0xd9: return this.str(this.uint8())
+        case 0xda: return this.str(this.advance(2, this.view.getUint16(this.offset)))
+        case 0xdb: return this.str(this.uint32())
+        case 0xdc: return this.array(this.advance(2, this.view.getUint16(this.offset)))
+        case 0xdd: return this.array(this.uint32())
+        case 0xde: return this.map(this.advance(2, this.view.getUint16(this.offset)))
+        case 0xdf: return this.map(this.uint32())
+        default:
+            throw new Error(`Received unsupported MessagePack type: ${type}`)
+        }
+    }
+
+    private advance<T> (length: number, value: T): T {
+        this.offset += length
+        return value
+    }
+
+    private uint8 (): number {
+        return this.advance(1, this.view.getUint8(this.offset))
+    }
+
+    private uint32 (): number {
+        return this.advance(4, this.view.getUint32(this.offset))
+    }
+
+    private array (length: number): any[] {
+        const array: any[] = []
+        for (let i = 0; i < length; i++) {
+            array.push(this.read())
+        }
+        return array
+    }
+
+    private map (length: number): Map<any, any> {
+        const map = new Map()
+        for (let i = 0; i < length; i++) {
+            const key = this.read()
+            map.set(key, this.read())
+        }
+        return map
+    }
+
+    private bin (length: number): Uint8Array {
+        const bytes = new Uint8Array(this.view.buffer, this.view.byteOffset + this.offset, length)
+        return this.advance(length, bytes)
+    }
+
+    private str (length: number): string {
+        const end = this.offset + length
+        let str = ""
+        while (this.offset < end) {
+            const byte = this.uint8()
+            let codePoint: number
+            if (byte < 0x80) {
+                codePoint = byte
+            } else if (byte < 0xe0) {
+                codePoint = ((byte & 0x1f) << 6) | (this.uint8() & 0x3f)
+            } else if (byte < 0xf0) {
+                codePoint = ((byte & 0x0f) << 12) | ((this.uint8() & 0x3f) << 6) | (this.uint8() & 0x3f)
+            } else {
+                codePoint = ((byte & 0x07) << 18) | ((this.uint8() & 0x3f) << 12) |
+                    ((this.uint8() & 0x3f) << 6) | (this.uint8() & 0x3f)
+            }
+            str += String.fromCodePoint(codePoint)
+        }
+        return str
+    }
+}
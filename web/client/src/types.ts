@@ -12,6 +12,7 @@ export interface IActor {
 export enum ActorKind {
     Player = "Player",
     Ball = "Ball",
+    Bot = "Bot",
 }
 
 export enum OpCode {
@@ -19,6 +20,36 @@ export enum OpCode {
     Spawn = "Spawn",
     Despawn = "Despawn",
     WorldUpdate = "WorldUpdate",
+    Score = "Score",
+    MatchState = "MatchState",
+    Pong = "Pong",
+    Ping = "Ping",
+    Chat = "Chat",
+}
+
+export enum Phase {
+    WaitingForPlayers = "WaitingForPlayers",
+    Countdown = "Countdown",
+    Playing = "Playing",
+    Paused = "Paused",
+    Finished = "Finished",
+}
+
+export interface IMatchState {
+    phase: Phase,
+    winner: Side | null,
+    ticks_left: number,
+    score: IScore,
+}
+
+export enum Side {
+    Left = "Left",
+    Right = "Right",
+}
+
+export interface IScore {
+    left: number,
+    right: number,
 }
 
 export enum Command {
@@ -26,10 +57,54 @@ export enum Command {
     MoveDown = "MoveDown",
 }
 
-export interface IClientMessage {
+export interface IInput {
     active: boolean,
     command: Command,
-    id: number
+    id: number,
+    tick: number,
+    // The tick of the state we were looking at, the server checks hits against it
+    seen_tick: number,
+}
+
+export type RoomRequest = "Create" | "Auto" | { Existing: string }
+
+export enum Format {
+    Json = "Json",
+    MessagePack = "MessagePack",
+}
+
+export type ClientMessage =
+    { Hello: { version: number, format: Format, session?: string } } |
+    { Input: IInput } |
+    { Ping: { id: number } } |
+    { Pong: { id: number } } |
+    { Join: RoomRequest } |
+    "Leave" |
+    { Chat: string } |
+    { Ack: number }
+
+export interface IGreeting {
+    version: number,
+    id: Id,
+    session: string,
+    actors: IActor[],
+    score: IScore,
+    room: string,
+}
+
+export interface IChat {
+    from: Id,
+    text: string,
+}
+
+export interface IWorldUpdate {
+    server_time: number,
+    last_input: number,
+    baseline: number | null,
+    actors: any,
+    removed: Id[],
+    // Only sent when the server simulates deterministically
+    checksum?: number,
 }
 
 export interface IServerMessage {